@@ -61,15 +61,10 @@ end
 function M.is_server_running(binary_path, file_path)
   -- Ensure absolute file path
   local absolute_file_path = vim.fn.fnamemodify(file_path, ':p')
-  -- Run test command
-  local cmd = vim.fn.shellescape(binary_path) .. ' --stop ' .. vim.fn.shellescape(absolute_file_path) .. ' 2>&1'
-  local output = vim.fn.system(cmd)
-  -- Check for "No server found" message
-  if string.match(output, 'No server found') then
-    return false
-  else
-    return true
-  end
+  -- Ask marv, which exits with 1 when no server is running for the file
+  local cmd = vim.fn.shellescape(binary_path) .. ' status ' .. vim.fn.shellescape(absolute_file_path) .. ' 2>&1'
+  vim.fn.system(cmd)
+  return vim.v.shell_error == 0
 end
 
 -- Start server
@@ -77,14 +72,12 @@ function M.start_server(binary_path, file_path)
   -- Ensure absolute file path
   local absolute_file_path = vim.fn.fnamemodify(file_path, ':p')
 
-  -- Construct command
-  local cmd = vim.fn.shellescape(binary_path) .. ' --start ' .. vim.fn.shellescape(absolute_file_path)
+  -- Construct command, tying the server's lifetime to this editor
+  local cmd = vim.fn.shellescape(binary_path) .. ' start --parent-pid ' .. vim.fn.getpid()
+    .. ' ' .. vim.fn.shellescape(absolute_file_path)
 
-  -- Execute command
+  -- Execute command, which returns once the server answers
   vim.fn.system(cmd)
-
-  -- Give the server a moment to start
-  vim.cmd('sleep 500m')
   vim.api.nvim_echo({{"marv: started preview server", "Normal"}}, false, {})
 end
 
@@ -94,7 +87,7 @@ function M.stop_server(binary_path, file_path)
   local absolute_file_path = vim.fn.fnamemodify(file_path, ':p')
 
   -- Construct command
-  local cmd = vim.fn.shellescape(binary_path) .. ' --stop ' .. vim.fn.shellescape(absolute_file_path)
+  local cmd = vim.fn.shellescape(binary_path) .. ' stop ' .. vim.fn.shellescape(absolute_file_path)
 
   -- Execute command
   vim.fn.system(cmd)
@@ -138,17 +131,15 @@ function M.toggle()
   end
 end
 
--- Kill all Marv processes
+-- Stop all marv servers
 function M.kill_all()
-  local os_name = M.detect_os()
-  if os_name == 'windows' then
-    -- Windows command to kill processes
-    vim.fn.system('taskkill /F /IM marv*.exe 2>&1')
-  else
-    -- Unix command to kill processes (Linux and macOS)
-    vim.fn.system('pkill -f "marv-.*-.*" 2>&1')
+  local binary_path = M.get_binary_path()
+  if not binary_path then
+    vim.api.nvim_echo({{"marv: could not find binary for your platform", "ErrorMsg"}}, false, {})
+    return
   end
-  vim.api.nvim_echo({{"marv: all processes terminated", "Normal"}}, false, {})
+  vim.fn.system(vim.fn.shellescape(binary_path) .. ' kill-all 2>&1')
+  vim.api.nvim_echo({{"marv: all servers stopped", "Normal"}}, false, {})
 end
 
 -- Setup function to create commands
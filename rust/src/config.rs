@@ -0,0 +1,525 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Context, Result};
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, warn};
+
+use crate::utils;
+
+// Configuration file names
+const CONFIG_FILE: &str = "config.toml";
+const PROJECT_CONFIG_FILE: &str = ".marv.toml";
+
+// Prefix for environment variable overrides (MARV_BIND, MARV_PORT_MIN, ...)
+const ENV_PREFIX: &str = "MARV_";
+
+// Keys a project's .marv.toml may set. Project files come with the documents
+// next to them, so they only change how a page looks and how changes are
+// picked up, never which programs run, where files go or what is served.
+const PROJECT_KEYS: &[&str] = &[
+    "theme",
+    "syntax_theme",
+    "max_width",
+    "include_depth",
+    "debounce_ms",
+    "watcher",
+    "poll_interval_ms",
+    "diagram_timeout_ms",
+    "fallback_encoding",
+];
+
+// Themes mermaid provides
+const MERMAID_THEMES: &[&str] = &["default", "dark", "forest", "neutral", "base"];
+
+// Keys that can be overridden from the environment
+const ENV_KEYS: &[&str] = &[
+    "bind",
+    "allowed_hosts",
+    "port_min",
+    "port_max",
+    "state_dir",
+    "runtime_dir",
+    "cache_dir",
+    "open_browser",
+    "browser",
+    "theme",
+    "syntax_theme",
+    "mermaid_url",
+    "max_width",
+    "stylesheet",
+    "template",
+    "debounce_ms",
+    "watcher",
+    "poll_interval_ms",
+    "include_depth",
+    "include_root",
+    "graphviz_command",
+    "plantuml_command",
+    "diagram_timeout_ms",
+    "fallback_encoding",
+    "idle_timeout_secs",
+    "tls",
+    "tls_cert",
+    "tls_key",
+    "sanitize",
+    "trust",
+    "log_level",
+    "log_max_kb",
+];
+
+// How file changes are detected
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatcherMode {
+    // Native notifications, or polling on network and container filesystems
+    // and when the native watcher fails
+    Auto,
+    // Native notifications only
+    Native,
+    // Always poll
+    Poll,
+}
+
+impl WatcherMode {
+    // Name used in configuration files and MARV_WATCHER
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatcherMode::Auto => "auto",
+            WatcherMode::Native => "native",
+            WatcherMode::Poll => "poll",
+        }
+    }
+}
+
+// When raw HTML in documents is sanitized
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SanitizeMode {
+    // On servers shared beyond this machine
+    Auto,
+    Always,
+    Never,
+}
+
+// Effective marv configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Address the preview server binds to
+    pub bind: IpAddr,
+    // Host names the server answers to besides localhost and its addresses,
+    // e.g. when it is reached through a container's host
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_hosts: Vec<String>,
+    // Range of ports searched for a free one
+    pub port_min: u16,
+    pub port_max: u16,
+    // Directory for files kept across restarts: logs and the generated
+    // certificate, and server records when there is no runtime directory
+    pub state_dir: PathBuf,
+    // Directory for running servers' records and tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_dir: Option<PathBuf>,
+    // Directory for caches, such as rendered diagrams
+    pub cache_dir: PathBuf,
+    // Open the preview in a browser when a server starts
+    pub open_browser: bool,
+    // Command the preview is opened with; $BROWSER or the system browser when
+    // not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser: Option<String>,
+    // Mermaid theme
+    pub theme: String,
+    // Syntect theme for code blocks
+    pub syntax_theme: String,
+    // Where the Mermaid script is loaded from
+    pub mermaid_url: String,
+    // CSS max-width of the page body
+    pub max_width: String,
+    // Global user stylesheet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stylesheet: Option<PathBuf>,
+    // Page template replacing the built-in skeleton
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<PathBuf>,
+    // Quiet period used to coalesce bursts of file events, in milliseconds
+    pub debounce_ms: u64,
+    // How file changes are detected
+    pub watcher: WatcherMode,
+    // How often files are checked when polling, in milliseconds
+    pub poll_interval_ms: u64,
+    // How deeply markdown includes may nest
+    pub include_depth: usize,
+    // Directory included files must be in; the document's directory when not
+    // set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_root: Option<PathBuf>,
+    // Executable used for dot and graphviz blocks
+    pub graphviz_command: String,
+    // Executable or .jar used for plantuml blocks
+    pub plantuml_command: String,
+    // How long a diagram tool may run, in milliseconds
+    pub diagram_timeout_ms: u64,
+    // Encoding assumed for files that have no byte order mark and aren't UTF-8
+    pub fallback_encoding: String,
+    // Stop a server once no browser has been connected for this long, in
+    // seconds (0 keeps it running)
+    pub idle_timeout_secs: u64,
+    // Serve HTTPS instead of HTTP
+    pub tls: bool,
+    // Certificate and private key (PEM) used for HTTPS; a self-signed
+    // certificate is generated when they are not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+    // When raw HTML is reduced to an allowlist of tags and attributes
+    pub sanitize: SanitizeMode,
+    // Trust the document: render raw HTML as written and let its scripts run
+    pub trust: bool,
+    // Level of background servers' logs (error, warn, info, debug or trace)
+    pub log_level: String,
+    // Size a server's log file may reach before it is rotated
+    pub log_max_kb: u64,
+
+    // Files and variables the configuration was loaded from
+    #[serde(skip)]
+    pub sources: Vec<String>,
+    // Whether rendered diagrams are cached in cache_dir; off for servers that
+    // write nothing to disk
+    #[serde(skip)]
+    pub cache_diagrams: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let config_dir = utils::file::get_config_dir();
+
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            allowed_hosts: Vec::new(),
+            port_min: 4000,
+            port_max: 4999,
+            state_dir: utils::file::get_state_dir(),
+            runtime_dir: utils::file::get_runtime_dir(),
+            cache_dir: utils::file::get_cache_dir(),
+            open_browser: true,
+            browser: None,
+            theme: "dark".to_string(),
+            syntax_theme: "base16-ocean.dark".to_string(),
+            mermaid_url: "https://cdn.jsdelivr.net/npm/mermaid@10/dist/mermaid.min.js".to_string(),
+            max_width: "800px".to_string(),
+            stylesheet: config_dir.as_ref().map(|dir| dir.join("marv.css")),
+            template: config_dir.as_ref().map(|dir| dir.join("template.html")),
+            debounce_ms: 100,
+            watcher: WatcherMode::Auto,
+            poll_interval_ms: 1000,
+            include_depth: 8,
+            include_root: None,
+            graphviz_command: "dot".to_string(),
+            plantuml_command: "plantuml".to_string(),
+            diagram_timeout_ms: 5000,
+            fallback_encoding: "windows-1252".to_string(),
+            idle_timeout_secs: 0,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            sanitize: SanitizeMode::Auto,
+            trust: false,
+            log_level: "info".to_string(),
+            log_max_kb: 1024,
+            sources: Vec::new(),
+            cache_diagrams: true,
+        }
+    }
+}
+
+impl Config {
+    // Load the configuration: defaults, then the global config file, then
+    // .marv.toml files from the outermost to the innermost directory of the
+    // document, then MARV_* environment variables
+    pub fn load(input_path: Option<&Path>) -> Result<Config> {
+        let mut table = toml::Table::new();
+        let mut sources = Vec::new();
+
+        if let Some(path) = global_config_path() {
+            if path.is_file() {
+                merge_file(&mut table, &path, None)?;
+                sources.push(path.display().to_string());
+            }
+        }
+
+        if let Some(input_path) = input_path {
+            for path in project_config_files(input_path) {
+                merge_file(&mut table, &path, Some(PROJECT_KEYS))?;
+                sources.push(path.display().to_string());
+            }
+        }
+
+        for key in ENV_KEYS {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Ok(value) = std::env::var(&name) {
+                table.insert(key.to_string(), parse_env_value(&value));
+                sources.push(name);
+            }
+        }
+
+        let mut config =
+            Config::deserialize(toml::Value::Table(table)).context("Invalid marv configuration")?;
+        config.sources = sources;
+        config.state_dir = expand_home(&config.state_dir);
+        config.runtime_dir = config.runtime_dir.as_deref().map(expand_home);
+        config.cache_dir = expand_home(&config.cache_dir);
+        config.stylesheet = config.stylesheet.as_deref().map(expand_home);
+        config.template = config.template.as_deref().map(expand_home);
+        config.include_root = config.include_root.as_deref().map(expand_home);
+        config.tls_cert = config.tls_cert.as_deref().map(expand_home);
+        config.tls_key = config.tls_key.as_deref().map(expand_home);
+
+        config.validate()?;
+
+        debug!("Loaded configuration: {:?}", config);
+        Ok(config)
+    }
+
+    // Check the values that deserializing alone doesn't
+    fn validate(&self) -> Result<()> {
+        if self.port_min > self.port_max {
+            return Err(anyhow!(
+                "Invalid port range {}-{}",
+                self.port_min,
+                self.port_max
+            ));
+        }
+
+        if !MERMAID_THEMES.contains(&self.theme.as_str()) {
+            return Err(anyhow!(
+                "Invalid theme {:?}: expected one of {}",
+                self.theme,
+                MERMAID_THEMES.join(", ")
+            ));
+        }
+
+        if !is_css_length(&self.max_width) {
+            return Err(anyhow!(
+                "Invalid max_width {:?}: expected a CSS length such as 800px or 60em",
+                self.max_width
+            ));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(anyhow!("tls_cert and tls_key must be set together"));
+        }
+
+        if Encoding::for_label(self.fallback_encoding.as_bytes()).is_none() {
+            return Err(anyhow!(
+                "Unknown fallback_encoding {:?}",
+                self.fallback_encoding
+            ));
+        }
+
+        if LevelFilter::from_str(&self.log_level).is_err() {
+            return Err(anyhow!("Unknown log_level {:?}", self.log_level));
+        }
+
+        Ok(())
+    }
+
+    // Directory holding running servers' records
+    pub fn server_dir(&self) -> &Path {
+        self.runtime_dir.as_deref().unwrap_or(&self.state_dir)
+    }
+
+    // Encoding assumed for files that aren't UTF-8
+    pub fn fallback_encoding(&self) -> &'static Encoding {
+        Encoding::for_label(self.fallback_encoding.as_bytes()).unwrap_or(encoding_rs::WINDOWS_1252)
+    }
+
+    // Level of background servers' logs
+    pub fn log_level(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.log_level).unwrap_or(LevelFilter::INFO)
+    }
+
+    // Whether raw HTML in documents is sanitized
+    pub fn sanitizes_html(&self) -> bool {
+        if self.trust {
+            return false;
+        }
+        match self.sanitize {
+            SanitizeMode::Auto => !self.bind.is_loopback(),
+            SanitizeMode::Always => true,
+            SanitizeMode::Never => false,
+        }
+    }
+
+    // Render the effective configuration as TOML
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).context("Failed to serialize configuration")
+    }
+}
+
+// Path of the global configuration file
+pub fn global_config_path() -> Option<PathBuf> {
+    utils::file::get_config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+// Merge the keys of a TOML file into the table. When `allowed` is given,
+// other keys are ignored with a warning.
+fn merge_file(table: &mut toml::Table, path: &Path, allowed: Option<&[&str]>) -> Result<()> {
+    let content =
+        fs::read_to_string(path).context(format!("Failed to read config file {:?}", path))?;
+    let file_table: toml::Table =
+        toml::from_str(&content).context(format!("Failed to parse config file {:?}", path))?;
+    debug!("Merging config file {:?}", path);
+    for (key, value) in file_table {
+        if allowed.is_some_and(|allowed| !allowed.contains(&key.as_str())) {
+            warn!(
+                "Ignoring {} in {:?}: it can only be set in the global config or as {}{}",
+                key,
+                path,
+                ENV_PREFIX,
+                key.to_uppercase()
+            );
+            continue;
+        }
+        table.insert(key, value);
+    }
+    Ok(())
+}
+
+// Find .marv.toml files above a document, outermost first
+fn project_config_files(input_path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = input_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .filter(|path| path.is_file())
+        .collect();
+    files.reverse();
+    files
+}
+
+// Whether a value is a plain CSS length such as 800px, 60em or 90%, or none
+fn is_css_length(value: &str) -> bool {
+    static CSS_LENGTH: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(
+            r"^(none|0|\d+(\.\d+)?(px|em|rem|ch|ex|vw|vh|vmin|vmax|%|pt|pc|cm|mm|in))$",
+        )
+        .expect("valid CSS length pattern")
+    });
+    CSS_LENGTH.is_match(value)
+}
+
+// Interpret an environment value as a TOML value, falling back to a string
+fn parse_env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+// Expand a leading ~ to the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagrams::DiagramTools;
+
+    #[test]
+    fn project_files_only_set_presentation_keys() {
+        let dir = utils::file::test_dir("project-config");
+        let path = dir.join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &path,
+            "theme = \"forest\"\n\
+             max_width = \"60em\"\n\
+             state_dir = \"/tmp/elsewhere\"\n\
+             runtime_dir = \"/tmp/elsewhere\"\n\
+             bind = \"0.0.0.0\"\n\
+             template = \"/tmp/template.html\"\n\
+             stylesheet = \"/tmp/marv.css\"\n\
+             graphviz_command = \"sh -c evil\"\n\
+             browser = \"sh -c evil\"\n\
+             trust = true\n\
+             sanitize = \"never\"\n\
+             mermaid_url = \"https://evil.example.com/x.js\"\n",
+        )
+        .unwrap();
+
+        let mut table = toml::Table::new();
+        merge_file(&mut table, &path, Some(PROJECT_KEYS)).unwrap();
+        let keys: Vec<&str> = table.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["max_width", "theme"]);
+
+        // The global config may set anything
+        let mut table = toml::Table::new();
+        merge_file(&mut table, &path, None).unwrap();
+        assert_eq!(table.len(), 12);
+    }
+
+    #[test]
+    fn max_width_must_be_a_css_length() {
+        for value in ["800px", "60em", "90%", "42.5rem", "none", "0"] {
+            assert!(is_css_length(value), "{}", value);
+        }
+        for value in [
+            "",
+            "800",
+            "800px;}",
+            "1px</style>",
+            "calc(100% - 1px)",
+            " 800px",
+        ] {
+            assert!(!is_css_length(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn theme_must_be_a_mermaid_theme() {
+        let dir = utils::file::test_dir("theme");
+        let path = dir.join(PROJECT_CONFIG_FILE);
+        let config_with = |content: &str| {
+            fs::write(&path, content).unwrap();
+            let mut table = toml::Table::new();
+            merge_file(&mut table, &path, Some(PROJECT_KEYS)).unwrap();
+            Config::deserialize(toml::Value::Table(table)).unwrap()
+        };
+
+        let config = config_with("theme = \"forest\"");
+        assert_eq!(config.theme, "forest");
+        assert!(config.validate().is_ok());
+
+        let config = config_with("theme = \"</script><script>alert(1)</script>\"");
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().starts_with("Invalid theme"), "{}", error);
+    }
+
+    #[test]
+    fn caches_diagrams_unless_a_server_opts_out() {
+        let config = Config::deserialize(toml::Value::Table(toml::Table::new())).unwrap();
+        assert!(config.cache_diagrams);
+        assert!(DiagramTools::load(&config).cache_dir.is_some());
+
+        // Not a setting files can change
+        let table: toml::Table = toml::from_str("cache_diagrams = false").unwrap();
+        assert!(Config::deserialize(toml::Value::Table(table)).is_err());
+
+        let config = Config {
+            cache_diagrams: false,
+            ..config
+        };
+        assert_eq!(DiagramTools::load(&config).cache_dir, None);
+    }
+}
@@ -0,0 +1,516 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::renderer::PAGE_IDS;
+use crate::utils;
+
+// Subdirectory of the cache directory holding rendered diagrams
+const CACHE_DIR: &str = "diagrams";
+
+// How many rendered diagrams the cache keeps, dropping the least recently
+// used first
+const CACHE_ENTRIES: usize = 500;
+
+// How often a running tool is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// SVG elements and attributes kept in a tool's output before it is inlined.
+// Diagrams only need shapes, text, links and effects, so scripts, event
+// handlers and embedded HTML are dropped whatever the tool produced.
+const SVG_TAGS: &[&str] = &[
+    "svg",
+    "g",
+    "defs",
+    "symbol",
+    "use",
+    "title",
+    "desc",
+    "a",
+    "image",
+    "path",
+    "polygon",
+    "polyline",
+    "line",
+    "rect",
+    "circle",
+    "ellipse",
+    "text",
+    "tspan",
+    "textPath",
+    "linearGradient",
+    "radialGradient",
+    "stop",
+    "pattern",
+    "marker",
+    "clipPath",
+    "mask",
+    "filter",
+    "feBlend",
+    "feColorMatrix",
+    "feComposite",
+    "feFlood",
+    "feGaussianBlur",
+    "feMerge",
+    "feMergeNode",
+    "feOffset",
+];
+const SVG_ATTRIBUTES: &[&str] = &[
+    "id",
+    "class",
+    "style",
+    "width",
+    "height",
+    "viewBox",
+    "preserveAspectRatio",
+    "transform",
+    "x",
+    "y",
+    "x1",
+    "y1",
+    "x2",
+    "y2",
+    "dx",
+    "dy",
+    "cx",
+    "cy",
+    "r",
+    "rx",
+    "ry",
+    "fx",
+    "fy",
+    "d",
+    "points",
+    "fill",
+    "fill-opacity",
+    "fill-rule",
+    "stroke",
+    "stroke-width",
+    "stroke-opacity",
+    "stroke-dasharray",
+    "stroke-linecap",
+    "stroke-linejoin",
+    "stroke-miterlimit",
+    "opacity",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "text-anchor",
+    "dominant-baseline",
+    "text-decoration",
+    "textLength",
+    "lengthAdjust",
+    "offset",
+    "stop-color",
+    "stop-opacity",
+    "gradientUnits",
+    "gradientTransform",
+    "patternUnits",
+    "markerWidth",
+    "markerHeight",
+    "markerUnits",
+    "refX",
+    "refY",
+    "orient",
+    "clip-path",
+    "clipPathUnits",
+    "mask",
+    "filter",
+    "filterUnits",
+    "in",
+    "in2",
+    "result",
+    "mode",
+    "type",
+    "values",
+    "operator",
+    "stdDeviation",
+    "flood-color",
+    "flood-opacity",
+    "href",
+    "xlink:href",
+    "xlink:title",
+    "target",
+];
+
+// Diagram languages rendered by a local tool
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tool {
+    Graphviz,
+    PlantUml,
+}
+
+impl Tool {
+    // The tool for a fenced block's language, if any
+    fn for_language(lang: &str) -> Option<Tool> {
+        match lang {
+            "dot" | "graphviz" => Some(Tool::Graphviz),
+            "plantuml" | "puml" => Some(Tool::PlantUml),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::Graphviz => "graphviz",
+            Tool::PlantUml => "plantuml",
+        }
+    }
+}
+
+// Local tools used to render diagram blocks
+#[derive(Debug, Clone)]
+pub struct DiagramTools {
+    // Graphviz executable, with any extra arguments
+    pub graphviz_command: String,
+    // PlantUML executable or .jar, with any extra arguments
+    pub plantuml_command: String,
+    // How long a tool may run before it is killed
+    pub timeout: Duration,
+    // Where rendered diagrams are cached, keyed by content hash
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl DiagramTools {
+    // Build the diagram tool settings from the configuration
+    pub fn load(config: &Config) -> DiagramTools {
+        DiagramTools {
+            graphviz_command: config.graphviz_command.clone(),
+            plantuml_command: config.plantuml_command.clone(),
+            timeout: Duration::from_millis(config.diagram_timeout_ms),
+            cache_dir: config
+                .cache_diagrams
+                .then(|| config.cache_dir.join(CACHE_DIR)),
+        }
+    }
+}
+
+// Replace dot, graphviz and plantuml code blocks in rendered HTML with inline
+// SVG. A block that fails to render is kept, with the error shown above it.
+pub fn render_diagram_blocks(html: &str, tools: &DiagramTools) -> String {
+    static CODE_BLOCK: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r#"<pre><code class="language-([^"]+)">([\s\S]*?)</code></pre>"#)
+            .expect("valid code block pattern")
+    });
+
+    CODE_BLOCK
+        .replace_all(html, |cap: &regex::Captures| {
+            let block = cap.get(0).unwrap().as_str();
+            let tool = match Tool::for_language(cap.get(1).unwrap().as_str()) {
+                Some(tool) => tool,
+                None => return block.to_string(),
+            };
+
+            let source = html_escape::decode_html_entities(cap.get(2).unwrap().as_str());
+            match render_cached(tool, &source, tools) {
+                Ok(svg) => format!(
+                    "<div class=\"diagram diagram-{}\">{}</div>",
+                    tool.name(),
+                    sanitize_svg(&svg)
+                ),
+                Err(e) => {
+                    warn!("Failed to render {} diagram: {:#}", tool.name(), e);
+                    format!(
+                        "<div class=\"marv-error\">{}</div>\n{}",
+                        html_escape::encode_text(&format!("{:#}", e)),
+                        block
+                    )
+                }
+            }
+        })
+        .into_owned()
+}
+
+// Render a diagram, reusing a cached SVG for the same tool and source
+fn render_cached(tool: Tool, source: &str, tools: &DiagramTools) -> Result<String> {
+    let command = match tool {
+        Tool::Graphviz => &tools.graphviz_command,
+        Tool::PlantUml => &tools.plantuml_command,
+    };
+    let key = format!(
+        "{:x}",
+        md5::compute(format!("{}\n{}\n{}", tool.name(), command, source))
+    );
+    let cache_path = tools
+        .cache_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{}.svg", key)));
+
+    if let Some((path, svg)) = cache_path
+        .as_ref()
+        .and_then(|path| Some((path, fs::read_to_string(path).ok()?)))
+    {
+        debug!("Using cached {} diagram {}", tool.name(), key);
+        // Mark it as recently used, so pruning keeps it
+        let _ = fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        return Ok(svg);
+    }
+
+    let started = Instant::now();
+    let svg = match tool {
+        Tool::Graphviz => run_tool(command, &["-Tsvg"], source, tools.timeout)?,
+        Tool::PlantUml => {
+            // PlantUML needs the @startuml/@enduml markers
+            let source = if source.trim_start().starts_with("@start") {
+                source.to_string()
+            } else {
+                format!("@startuml\n{}@enduml\n", source)
+            };
+            run_tool(command, &["-tsvg", "-pipe"], &source, tools.timeout)?
+        }
+    };
+    let svg = strip_xml_prolog(&svg).to_string();
+    info!(
+        "Rendered {} diagram in {:?}",
+        tool.name(),
+        started.elapsed()
+    );
+
+    if let Some(path) = cache_path {
+        let written = path
+            .parent()
+            .map_or(Ok(()), utils::file::create_private_subdir)
+            .and_then(|_| fs::write(&path, &svg).context("Failed to write diagram"));
+        match written {
+            Ok(()) => prune_cache(path.parent().unwrap_or(Path::new(".")), CACHE_ENTRIES),
+            Err(e) => debug!("Failed to cache diagram at {:?}: {:#}", path, e),
+        }
+    }
+
+    Ok(svg)
+}
+
+// Remove the least recently used diagrams from the cache, keeping `keep`
+fn prune_cache(dir: &Path, keep: usize) {
+    let mut entries: Vec<(SystemTime, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "svg"))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    if entries.len() <= keep {
+        return;
+    }
+
+    entries.sort();
+    for (_, path) in &entries[..entries.len() - keep] {
+        match fs::remove_file(path) {
+            Ok(()) => debug!("Removed cached diagram {:?}", path),
+            Err(e) => debug!("Failed to remove cached diagram {:?}: {}", path, e),
+        }
+    }
+}
+
+// Reduce a tool's SVG to drawing elements before it is inlined in the page.
+// Ids the page uses for its own elements are dropped, as a node can be given
+// any id.
+fn sanitize_svg(svg: &str) -> String {
+    ammonia::Builder::default()
+        .tags(SVG_TAGS.iter().copied().collect())
+        .generic_attributes(SVG_ATTRIBUTES.iter().copied().collect())
+        .attribute_filter(|_, attribute, value| {
+            if attribute == "id" && PAGE_IDS.contains(&value) {
+                None
+            } else {
+                Some(value.into())
+            }
+        })
+        .url_schemes(["http", "https", "mailto"].into_iter().collect())
+        .link_rel(None)
+        .clean(svg)
+        .to_string()
+}
+
+// Run a tool with the source on stdin, returning its stdout. The tool is
+// killed if it runs longer than the timeout.
+fn run_tool(command: &str, args: &[&str], input: &str, timeout: Duration) -> Result<String> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| anyhow!("No command configured"))?;
+
+    // A .jar is run with java, as PlantUML is usually distributed that way
+    let mut cmd = if program.ends_with(".jar") {
+        let mut cmd = Command::new("java");
+        cmd.args(["-Djava.awt.headless=true", "-jar", program]);
+        cmd
+    } else {
+        Command::new(program)
+    };
+    cmd.args(words)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = cmd
+        .spawn()
+        .context(format!("Failed to run {:?}", program))?;
+
+    // Feed stdin and drain the output on threads so a chatty tool can't block
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stdout.read_to_end(&mut output);
+        output
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        output
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("{} timed out after {:?}", program, timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(anyhow!(
+            "{} failed ({}): {}",
+            program,
+            status,
+            stderr.trim()
+        ));
+    }
+
+    String::from_utf8(stdout).context(format!("{} produced invalid output", program))
+}
+
+// Drop the XML declaration and doctype so the SVG can be inlined
+fn strip_xml_prolog(svg: &str) -> &str {
+    match svg.find("<svg") {
+        Some(start) => &svg[start..],
+        None => svg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_drawing_elements_of_svg() {
+        let svg = sanitize_svg(
+            r##"<svg viewBox="0 0 10 10" onload="alert(1)">
+<g class="node"><title>a</title><ellipse cx="5" cy="5" rx="5" ry="5" fill="none"/>
+<text x="5" y="5" font-size="14.00">a&lt;b</text></g>
+<a xlink:href="https://example.com/"><text>ok</text></a>
+<a xlink:href="javascript:alert(1)"><text>bad</text></a>
+<script>alert(2)</script>
+<foreignObject><div onclick="alert(3)">html</div></foreignObject>
+</svg>"##,
+        );
+
+        assert!(svg.starts_with(r#"<svg viewBox="0 0 10 10">"#));
+        assert!(svg.contains(r#"<ellipse cx="5" cy="5" rx="5" ry="5" fill="none"></ellipse>"#));
+        assert!(svg.contains("a&lt;b</text>"));
+        assert!(svg.contains(r#"<a xlink:href="https://example.com/">"#));
+        for removed in ["alert", "javascript", "script", "foreignObject", "html"] {
+            assert!(!svg.contains(removed), "{} in {}", removed, svg);
+        }
+    }
+
+    #[test]
+    fn drops_the_page_ids_from_svg() {
+        // What dot renders for `digraph { content [id="content"]; b }`
+        let svg = sanitize_svg(
+            r#"<svg viewBox="0 0 62 44"><g id="graph0" class="graph">
+<g id="content" class="node"><title>content</title><ellipse cx="27" cy="-18" rx="27" ry="18"/></g>
+<g id="node2" class="node"><title>b</title><ellipse cx="27" cy="-90" rx="27" ry="18"/></g>
+</g></svg>"#,
+        );
+
+        assert!(svg.contains(r#"<g class="node"><title>content</title>"#));
+        assert!(svg.contains(r#"<g id="node2" class="node">"#));
+        assert!(svg.contains(r#"<g id="graph0" class="graph">"#));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn sanitizes_what_a_tool_renders() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A "tool" that renders its source as is
+        let dir = utils::file::test_dir("diagram-tool");
+        let tool = dir.join("echo-svg");
+        fs::write(&tool, "#!/bin/sh\ncat\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let tools = DiagramTools {
+            graphviz_command: tool.display().to_string(),
+            plantuml_command: "plantuml".to_string(),
+            timeout: Duration::from_secs(5),
+            cache_dir: Some(dir.join("cache").join(CACHE_DIR)),
+        };
+
+        let html = r#"<pre><code class="language-dot">&lt;svg&gt;&lt;script&gt;alert(1)&lt;/script&gt;&lt;rect width="1"/&gt;&lt;/svg&gt;</code></pre>"#;
+        let expected =
+            r#"<div class="diagram diagram-graphviz"><svg><rect width="1"></rect></svg></div>"#;
+        assert_eq!(render_diagram_blocks(html, &tools), expected);
+        // Including when it comes from the cache
+        let cache_dir = dir.join("cache").join(CACHE_DIR);
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+        assert_eq!(render_diagram_blocks(html, &tools), expected);
+
+        // Which only the user can read
+        let mode = fs::metadata(&cache_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn prunes_the_least_recently_used_diagrams() {
+        let dir = utils::file::test_dir("diagram-cache");
+        let now = SystemTime::now();
+        for (index, name) in ["old", "older", "new", "newer"].iter().enumerate() {
+            let path = dir.join(format!("{}.svg", name));
+            fs::write(&path, "<svg></svg>").unwrap();
+            let age = match *name {
+                "older" => 400,
+                "old" => 300,
+                "new" => 200,
+                _ => 100,
+            };
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(age + index as u64))
+                .unwrap();
+        }
+
+        prune_cache(&dir, 2);
+        let mut kept: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["new.svg", "newer.svg"]);
+    }
+}
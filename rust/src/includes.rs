@@ -0,0 +1,657 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Context, Result};
+use encoding_rs::Encoding;
+use regex::Regex;
+use tracing::debug;
+
+use crate::utils::file;
+
+// <!-- include: path --> and ![[name]] lines, indented less than a code block
+static INCLUDE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^ {0,3}<!--\s*include:\s*(.+?)\s*-->\s*$").expect("valid include pattern")
+});
+static EMBED: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^ {0,3}!\[\[([^\]|#]+)(?:#[^\]|]*)?(?:\|[^\]]*)?\]\]\s*$")
+        .expect("valid embed pattern")
+});
+
+// Link and image destinations, and link reference definitions
+static LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\]\([ \t]*)(<[^>]*>|[^\s)]+)").expect("valid link pattern"));
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^( {0,3}\[[^\]]+\]:[ \t]*)(<[^>]*>|\S+)").expect("valid reference pattern")
+});
+
+// Markdown with include directives expanded
+#[derive(Debug, Clone, Default)]
+pub struct Expanded {
+    pub markdown: String,
+    // Files spliced into the document
+    pub dependencies: BTreeSet<PathBuf>,
+    // Line of the document each line of the markdown comes from, counting
+    // from 1; included lines come from their directive's line
+    pub source_lines: Vec<usize>,
+}
+
+// Settings and state shared by nested includes
+struct Include {
+    // Files currently being expanded, outermost first
+    stack: Vec<PathBuf>,
+    max_depth: usize,
+    encoding: &'static Encoding,
+    // Directory that included files must be in
+    root: PathBuf,
+    // The document's directory, which the page's relative links resolve from
+    document_dir: PathBuf,
+}
+
+// An open fenced code block
+struct Fence {
+    marker: char,
+    length: usize,
+    // The fence written in place of the closing one when the body is
+    // replaced by a code include
+    closing: Option<String>,
+}
+
+// Expand include directives in a document:
+//
+//   <!-- include: ./part.md -->       splices in another markdown file
+//   ![[note]]                         embeds note.md (or an image)
+//   ```rust file=src/lib.rs#L10-L40   fills a code block from a source file
+//
+// Markdown includes are expanded recursively, relative to the including file,
+// up to `max_depth` levels, and their relative links and images are rewritten
+// to point at the same files from the document's directory. Only files under
+// `root` (the document's directory when not given) can be included, so a
+// document can't pull in, say, a key from the home directory. Included files
+// that aren't UTF-8 are decoded with `encoding`. Failures are shown inline in
+// the document.
+pub fn expand_includes(
+    markdown: &str,
+    document_path: &Path,
+    root: Option<&Path>,
+    max_depth: usize,
+    encoding: &'static Encoding,
+) -> Expanded {
+    let mut expanded = Expanded::default();
+    let document_dir = document_path.parent().unwrap_or(Path::new("."));
+    let mut include = Include {
+        stack: vec![canonical(document_path)],
+        max_depth,
+        encoding,
+        root: canonical(root.unwrap_or(document_dir)),
+        document_dir: canonical(document_dir),
+    };
+
+    expanded.markdown = expand(
+        markdown,
+        document_dir,
+        &mut include,
+        &mut expanded.dependencies,
+        &mut expanded.source_lines,
+    );
+    expanded
+}
+
+// Expand the directives in one file's content, noting the line of the file
+// each output line comes from in `source_lines`
+fn expand(
+    markdown: &str,
+    base_dir: &Path,
+    include: &mut Include,
+    dependencies: &mut BTreeSet<PathBuf>,
+    source_lines: &mut Vec<usize>,
+) -> String {
+    let mut output = String::with_capacity(markdown.len());
+    let mut fence: Option<Fence> = None;
+    let mut noted = 0;
+    let link_prefix = link_prefix(&include.document_dir, &canonical(base_dir));
+
+    let mut line_count = 0;
+    for (index, line) in markdown.split_inclusive('\n').enumerate() {
+        // Everything written since the last line came from that line
+        note_lines(source_lines, &output, &mut noted, index);
+        line_count = index + 1;
+        let content = line.trim_end_matches(['\n', '\r']);
+
+        // Inside a fenced block nothing is expanded
+        if let Some(open) = &fence {
+            if is_closing_fence(content, open) {
+                output.push_str(open.closing.as_deref().unwrap_or(line));
+                fence = None;
+            } else if open.closing.is_none() {
+                output.push_str(line);
+            }
+            continue;
+        }
+
+        if let Some((marker, length, info)) = parse_opening_fence(content) {
+            let closing = match code_include_spec(info) {
+                Some(spec) => {
+                    let code = match read_code_include(base_dir, spec, include, dependencies) {
+                        Ok(code) => code,
+                        Err(e) => format!("{:#}\n", e),
+                    };
+                    // Longer than any run of the marker in the code, so the
+                    // code can't close the block early
+                    let indent = &content[..content.len() - content.trim_start().len()];
+                    let fence = marker
+                        .to_string()
+                        .repeat(length.max(longest_run(&code, marker) + 1));
+                    output.push_str(&format!("{}{}{}\n{}", indent, fence, info, code));
+                    Some(format!("{}{}\n", indent, fence))
+                }
+                None => {
+                    output.push_str(line);
+                    if !line.ends_with('\n') {
+                        output.push('\n');
+                    }
+                    None
+                }
+            };
+            fence = Some(Fence {
+                marker,
+                length,
+                closing,
+            });
+            continue;
+        }
+
+        let target = if let Some(cap) = INCLUDE.captures(content) {
+            Some(cap.get(1).unwrap().as_str().to_string())
+        } else if let Some(cap) = EMBED.captures(content) {
+            let name = cap.get(1).unwrap().as_str().trim();
+            if is_markdown_target(name) {
+                Some(name.to_string())
+            } else if Path::new(name).extension().is_some() {
+                // Embedded images and other files become plain image links
+                let target = format!("{}{}", link_prefix, name).replace(' ', "%20");
+                output.push_str(&format!("![{}]({})\n", name, target));
+                continue;
+            } else {
+                Some(format!("{}.md", name))
+            }
+        } else {
+            None
+        };
+
+        match target {
+            Some(target) => {
+                match include_markdown(base_dir, &target, include, dependencies) {
+                    Ok(included) => output.push_str(&included),
+                    Err(e) => output.push_str(&include_error(&format!("{:#}", e))),
+                }
+                if !output.ends_with('\n') {
+                    output.push('\n');
+                }
+            }
+            None if link_prefix.is_empty() => output.push_str(line),
+            None => output.push_str(&rewrite_links(line, &link_prefix)),
+        }
+    }
+
+    note_lines(source_lines, &output, &mut noted, line_count);
+    if !output.is_empty() && !output.ends_with('\n') {
+        source_lines.push(line_count);
+    }
+    output
+}
+
+// Note that the lines written to `output` since `noted` come from `line`
+fn note_lines(source_lines: &mut Vec<usize>, output: &str, noted: &mut usize, line: usize) {
+    let written = output[*noted..].matches('\n').count();
+    source_lines.extend(std::iter::repeat_n(line, written));
+    *noted = output.len();
+}
+
+// Read and expand an included markdown file
+fn include_markdown(
+    base_dir: &Path,
+    target: &str,
+    include: &mut Include,
+    dependencies: &mut BTreeSet<PathBuf>,
+) -> Result<String> {
+    let path = resolve(base_dir, target, include, dependencies)?;
+    if include.stack.contains(&path) {
+        return Err(anyhow!("Cannot include {}: include cycle", target));
+    }
+    if include.stack.len() > include.max_depth {
+        return Err(anyhow!(
+            "Cannot include {}: include depth limit ({}) exceeded",
+            target,
+            include.max_depth
+        ));
+    }
+
+    let content = file::decode_file(&path, include.encoding)
+        .context(format!("Cannot include {}", target))?
+        .content;
+    debug!("Including {:?}", path);
+
+    include.stack.push(path.clone());
+    let expanded = expand(
+        &content,
+        path.parent().unwrap_or(Path::new(".")),
+        include,
+        dependencies,
+        &mut Vec::new(),
+    );
+    include.stack.pop();
+
+    Ok(expanded)
+}
+
+// Find an included file relative to the including file's directory. It must
+// be under the include root once symlinks are resolved. Missing files are
+// still recorded, so creating them triggers a refresh.
+fn resolve(
+    base_dir: &Path,
+    target: &str,
+    include: &Include,
+    dependencies: &mut BTreeSet<PathBuf>,
+) -> Result<PathBuf> {
+    let path = match fs::canonicalize(base_dir.join(target)) {
+        Ok(path) => path,
+        Err(_) => {
+            dependencies.insert(base_dir.join(target));
+            return Err(anyhow!("Cannot include {}: not found", target));
+        }
+    };
+    if !path.starts_with(&include.root) {
+        return Err(anyhow!(
+            "Cannot include {}: only files in {} can be included",
+            target,
+            include.root.display()
+        ));
+    }
+    dependencies.insert(path.clone());
+    Ok(path)
+}
+
+// The path from the document's directory to an included file's directory,
+// with a trailing slash, to put in front of the file's relative links. Empty
+// for files in the document's directory.
+fn link_prefix(document_dir: &Path, dir: &Path) -> String {
+    let common = document_dir
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut prefix = String::new();
+    for _ in document_dir.components().skip(common) {
+        prefix.push_str("../");
+    }
+    for component in dir.components().skip(common) {
+        prefix.push_str(&component.as_os_str().to_string_lossy());
+        prefix.push('/');
+    }
+    prefix
+}
+
+// Point the relative link and image destinations in a line of an included
+// file at the same files from the document's directory. Code spans are left
+// alone.
+fn rewrite_links(line: &str, prefix: &str) -> String {
+    let rewrite = |caps: &regex::Captures| {
+        let (lead, target) = (&caps[1], &caps[2]);
+        match target.strip_prefix('<') {
+            Some(target) if is_relative_link(target) => format!("{}<{}{}", lead, prefix, target),
+            None if is_relative_link(target) => {
+                format!("{}{}{}", lead, prefix.replace(' ', "%20"), target)
+            }
+            _ => caps[0].to_string(),
+        }
+    };
+
+    let line = REFERENCE.replace(line, rewrite);
+    line.split('`')
+        .enumerate()
+        .map(|(index, part)| match index % 2 {
+            0 => LINK.replace_all(part, rewrite),
+            _ => part.into(),
+        })
+        .collect::<Vec<_>>()
+        .join("`")
+}
+
+// Whether a link destination is relative to the file it is written in
+fn is_relative_link(target: &str) -> bool {
+    let scheme = target.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    });
+    !(target.is_empty() || target.starts_with(['#', '/']) || scheme)
+}
+
+// Read the lines of a source file for a code include such as src/lib.rs#L10-L40
+fn read_code_include(
+    base_dir: &Path,
+    spec: &str,
+    include: &Include,
+    dependencies: &mut BTreeSet<PathBuf>,
+) -> Result<String> {
+    let (target, range) = match spec.split_once('#') {
+        Some((target, range)) => (target, Some(range)),
+        None => (spec, None),
+    };
+
+    let path = resolve(base_dir, target, include, dependencies)?;
+    let content = file::decode_file(&path, include.encoding)
+        .context(format!("Cannot include {}", target))?
+        .content;
+    let lines: Vec<&str> = content.lines().collect();
+
+    let (start, end) = match range {
+        Some(range) => parse_line_range(range)
+            .ok_or_else(|| anyhow!("Cannot include {}: invalid line range #{}", target, range))?,
+        None => (1, lines.len()),
+    };
+    if start == 0 || start > lines.len() || end < start {
+        return Err(anyhow!(
+            "Cannot include {}: lines {}-{} are outside the file ({} lines)",
+            target,
+            start,
+            end,
+            lines.len()
+        ));
+    }
+
+    let end = end.min(lines.len());
+    let mut code = lines[start - 1..end].join("\n");
+    code.push('\n');
+    Ok(code)
+}
+
+// Parse L10, L10-L40 or L10-40
+fn parse_line_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start, Some(end)),
+        None => (range, None),
+    };
+    let start = start.strip_prefix('L')?.parse().ok()?;
+    let end = match end {
+        Some(end) => end.strip_prefix('L').unwrap_or(end).parse().ok()?,
+        None => start,
+    };
+    Some((start, end))
+}
+
+// Find a file=... attribute in a fenced block's info string
+fn code_include_spec(info: &str) -> Option<&str> {
+    info.split_whitespace()
+        .find_map(|word| word.strip_prefix("file="))
+        .filter(|spec| !spec.is_empty())
+}
+
+// Parse an opening code fence, returning its marker, length and info string
+fn parse_opening_fence(line: &str) -> Option<(char, usize, &str)> {
+    let trimmed = line.trim_start();
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = trimmed.chars().take_while(|c| *c == marker).count();
+    if length < 3 {
+        return None;
+    }
+
+    let info = trimmed[length..].trim();
+    if marker == '`' && info.contains('`') {
+        return None;
+    }
+    Some((marker, length, info))
+}
+
+// Whether a line closes the open fence
+fn is_closing_fence(line: &str, fence: &Fence) -> bool {
+    let trimmed = line.trim();
+    let length = trimmed.chars().take_while(|c| *c == fence.marker).count();
+    length >= fence.length && trimmed.chars().all(|c| c == fence.marker)
+}
+
+// The longest run of a character in some text
+fn longest_run(text: &str, marker: char) -> usize {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == marker { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    longest
+}
+
+// The canonical form of a path, or the path itself if it doesn't exist
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Whether an embed target names a markdown file
+fn is_markdown_target(name: &str) -> bool {
+    matches!(
+        Path::new(name).extension().and_then(|e| e.to_str()),
+        Some("md") | Some("markdown")
+    )
+}
+
+// An inline error block shown in place of a failed include
+fn include_error(message: &str) -> String {
+    format!(
+        "\n<div class=\"marv-error\">{}</div>\n\n",
+        html_escape::encode_text(message)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write files into a new test directory
+    fn files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = file::test_dir(name);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    // Expand the includes of a document in a test directory
+    fn expand_document(dir: &Path, name: &str, max_depth: usize) -> String {
+        let path = dir.join(name);
+        let markdown = fs::read_to_string(&path).unwrap();
+        expand_includes(&markdown, &path, None, max_depth, encoding_rs::WINDOWS_1252).markdown
+    }
+
+    #[test]
+    fn reports_a_file_including_itself() {
+        let dir = files("include-self", &[("a.md", "A\n<!-- include: a.md -->\n")]);
+        let output = expand_document(&dir, "a.md", 8);
+        assert!(output.starts_with("A\n"));
+        assert!(output.contains("Cannot include a.md: include cycle"));
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let dir = files(
+            "include-cycle",
+            &[
+                ("a.md", "A\n<!-- include: b.md -->\n"),
+                ("b.md", "B\n<!-- include: a.md -->\n"),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 8);
+        assert!(output.contains("A\nB\n"));
+        assert!(output.contains("Cannot include a.md: include cycle"));
+    }
+
+    #[test]
+    fn stops_at_the_include_depth() {
+        let dir = files(
+            "include-depth",
+            &[
+                ("a.md", "A\n<!-- include: b.md -->\n"),
+                ("b.md", "B\n![[c]]\n"),
+                ("c.md", "C\n"),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 1);
+        assert!(output.contains("A\nB\n"));
+        assert!(!output.contains("C\n"));
+        assert!(output.contains("Cannot include c.md: include depth limit (1) exceeded"));
+
+        assert!(expand_document(&dir, "a.md", 2).contains("A\nB\nC\n"));
+    }
+
+    #[test]
+    fn rewrites_relative_links_in_included_files() {
+        let dir = files(
+            "include-links",
+            &[
+                ("a.md", "![top](top.png)\n<!-- include: parts/b.md -->\n"),
+                (
+                    "parts/b.md",
+                    "![cat](cat.png) and [next](c.md#top) or [up](../a.md)\n\
+                     [web](https://example.com) [anchor](#a) [root](/x.png) \\\n\
+                     `[code](span.md)` [![icon](icon.png)](<big icon.png>)\n\
+                     ![[pic.png]]\n\
+                     [ref]: ref.png \"Title\"\n\
+                     ```\n![fenced](fenced.png)\n```\n",
+                ),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 8);
+        assert_eq!(
+            output,
+            "![top](top.png)\n\
+             ![cat](parts/cat.png) and [next](parts/c.md#top) or [up](parts/../a.md)\n\
+             [web](https://example.com) [anchor](#a) [root](/x.png) \\\n\
+             `[code](span.md)` [![icon](parts/icon.png)](<parts/big icon.png>)\n\
+             ![pic.png](parts/pic.png)\n\
+             [ref]: parts/ref.png \"Title\"\n\
+             ```\n![fenced](fenced.png)\n```\n"
+        );
+        assert_eq!(
+            link_prefix(Path::new("/d/a"), Path::new("/d/b c")),
+            "../b c/"
+        );
+    }
+
+    #[test]
+    fn leaves_directives_in_indented_code_alone() {
+        let dir = files(
+            "include-indented",
+            &[
+                (
+                    "a.md",
+                    "   <!-- include: b.md -->\n\n    <!-- include: b.md -->\n\t![[b]]\n",
+                ),
+                ("b.md", "B\n"),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 8);
+        assert_eq!(output, "B\n\n    <!-- include: b.md -->\n\t![[b]]\n");
+    }
+
+    #[test]
+    fn includes_line_ranges_of_source_files() {
+        let dir = files(
+            "include-lines",
+            &[
+                ("src/lib.rs", "one\ntwo\nthree\nfour\n"),
+                (
+                    "a.md",
+                    "```rust file=src/lib.rs#L2-L3\nreplaced\n```\n\
+                     ```rust file=src/lib.rs#L4\n```\n\
+                     ```rust file=src/lib.rs#L9\n```\n",
+                ),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 8);
+        assert_eq!(
+            output,
+            "```rust file=src/lib.rs#L2-L3\ntwo\nthree\n```\n\
+             ```rust file=src/lib.rs#L4\nfour\n```\n\
+             ```rust file=src/lib.rs#L9\n\
+             Cannot include src/lib.rs: lines 9-9 are outside the file (4 lines)\n```\n"
+        );
+        assert_eq!(parse_line_range("L10-40"), Some((10, 40)));
+        assert_eq!(parse_line_range("10"), None);
+    }
+
+    #[test]
+    fn fences_included_code_that_contains_fences() {
+        let dir = files(
+            "include-fence",
+            &[
+                ("README.md", "Intro\n````\n```\n````\n"),
+                ("a.md", "```markdown file=README.md\n```\nafter\n"),
+            ],
+        );
+        let output = expand_document(&dir, "a.md", 8);
+        assert_eq!(
+            output,
+            "`````markdown file=README.md\nIntro\n````\n```\n````\n`````\nafter\n"
+        );
+    }
+
+    #[test]
+    fn only_includes_files_under_the_document_directory() {
+        let dir = files(
+            "include-root",
+            &[("secret.txt", "secret\n"), ("docs/a.md", "")],
+        );
+        fs::write(
+            dir.join("docs/a.md"),
+            format!(
+                "<!-- include: ../secret.txt -->\n<!-- include: {} -->\n\
+                 ```text file=../secret.txt\n```\n",
+                dir.join("secret.txt").display()
+            ),
+        )
+        .unwrap();
+
+        let output = expand_document(&dir.join("docs"), "a.md", 8);
+        assert!(!output.contains("secret\n"));
+        assert_eq!(output.matches("only files in").count(), 3);
+
+        // Unless the root is widened
+        let path = dir.join("docs/a.md");
+        let markdown = fs::read_to_string(&path).unwrap();
+        let expanded = expand_includes(&markdown, &path, Some(&dir), 8, encoding_rs::WINDOWS_1252);
+        assert_eq!(expanded.markdown.matches("secret\n").count(), 3);
+    }
+
+    #[test]
+    fn maps_expanded_lines_to_the_document() {
+        let dir = files(
+            "include-source-lines",
+            &[
+                (
+                    "a.md",
+                    "A\n<!-- include: b.md -->\n```text file=c.txt\nold\n```\nD",
+                ),
+                ("b.md", "B1\n<!-- include: c.md -->\n"),
+                ("c.md", "C1\nC2\n"),
+                ("c.txt", "x\ny\n"),
+            ],
+        );
+        let path = dir.join("a.md");
+        let markdown = fs::read_to_string(&path).unwrap();
+        let expanded = expand_includes(&markdown, &path, None, 8, encoding_rs::WINDOWS_1252);
+
+        assert_eq!(
+            expanded.markdown,
+            "A\nB1\nC1\nC2\n```text file=c.txt\nx\ny\n```\nD"
+        );
+        assert_eq!(expanded.source_lines, [1, 2, 2, 2, 3, 3, 3, 5, 6]);
+    }
+}
@@ -0,0 +1,14 @@
+// marv renders Markdown and Mermaid documents to HTML and serves live
+// previews of them. The marv binary is a command line interface over this
+// library, which other tools can use to render or serve documents themselves.
+
+pub mod config;
+pub mod diagrams;
+pub mod includes;
+pub mod logs;
+pub mod protocol;
+pub mod renderer;
+pub mod server;
+pub mod tls;
+pub mod utils;
+pub mod watcher;
@@ -0,0 +1,281 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+use crate::config::Config;
+use crate::utils;
+
+// Number of rotated log files kept next to the current one (.log.1, .log.2)
+const ROTATED_LOGS: usize = 2;
+
+// How often a followed log is checked for new lines
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+// Subdirectory of the state directory holding server logs
+const LOG_DIR: &str = "logs";
+
+// Log file of the server for a document, kept in the state directory so that
+// it outlives the server
+pub fn log_path(config: &Config, input_path: &Path) -> PathBuf {
+    config
+        .state_dir
+        .join(LOG_DIR)
+        .join(format!("{}.log", utils::file::get_file_hash(input_path)))
+}
+
+// Send this process's logs to a file, for background servers whose output
+// goes nowhere
+pub fn init_file_logging(path: &Path, config: &Config) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        utils::file::create_private_subdir(dir)?;
+    }
+    let file = RotatingFile::open(path, config.log_max_kb * 1024)?;
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(Mutex::new(file))
+        .with_ansi(false);
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(filter(config))
+        .try_init()
+        .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
+
+// Send this process's logs to stderr at the configured level, for servers
+// run in the foreground, leaving stdout to the URLs
+pub fn init_stderr_logging(config: &Config) -> Result<()> {
+    let layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(filter(config))
+        .try_init()
+        .map_err(|e| anyhow!("Failed to set up logging: {}", e))
+}
+
+// marv's own events at the configured level. Other crates only log warnings,
+// so that debug logs aren't mostly HTTP internals.
+fn filter(config: &Config) -> Targets {
+    let level = config.log_level();
+    Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(level.min(LevelFilter::WARN))
+}
+
+// A log file that is moved aside once it grows past a size, keeping a few
+// older files
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64) -> Result<RotatingFile> {
+        let file = append(path).context(format!("Failed to open log file {:?}", path))?;
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_bytes,
+            file,
+            size,
+        })
+    }
+
+    // Shift .log.1 to .log.2 and so on, dropping the oldest, then start a
+    // new file
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..ROTATED_LOGS).rev() {
+            let from = rotated_path(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+// Print the last lines of a log, then, when following, lines as they are
+// written until interrupted
+pub fn tail(path: &Path, lines: usize, follow: bool) -> Result<()> {
+    let mut file = File::open(path).context(format!("Failed to read log file {:?}", path))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .context(format!("Failed to read log file {:?}", path))?;
+    let content = String::from_utf8_lossy(&bytes);
+    let skip = content.lines().count().saturating_sub(lines);
+    for line in content.lines().skip(skip) {
+        println!("{}", line);
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut follower = Follower {
+        path: path.to_path_buf(),
+        file,
+        position: bytes.len() as u64,
+    };
+    let mut stdout = io::stdout();
+    loop {
+        std::thread::sleep(FOLLOW_INTERVAL);
+        follower.copy_new(&mut stdout)?;
+        stdout.flush()?;
+    }
+}
+
+// An open log being followed across rotations
+struct Follower {
+    path: PathBuf,
+    file: File,
+    position: u64,
+}
+
+impl Follower {
+    // Copy what has been written since the last call. When the log has been
+    // rotated, the rest of the old file is copied before moving on to the
+    // new one, so no lines are lost; a truncated log is read from the start.
+    fn copy_new(&mut self, out: &mut impl Write) -> Result<()> {
+        let current = self.file.metadata()?;
+        let (rotated, truncated) = match fs::metadata(&self.path) {
+            Ok(metadata) if !same_file(&metadata, &current) => (true, false),
+            Ok(metadata) => (false, metadata.len() < self.position),
+            // Between rotating and creating the new file
+            Err(_) => (false, false),
+        };
+        if truncated {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.position = 0;
+        }
+
+        let mut new = Vec::new();
+        self.file.read_to_end(&mut new)?;
+        self.position += new.len() as u64;
+        out.write_all(&new)?;
+
+        if rotated {
+            self.file = File::open(&self.path)
+                .context(format!("Failed to read log file {:?}", self.path))?;
+            self.position = 0;
+            self.copy_new(out)?;
+        }
+        Ok(())
+    }
+}
+
+// Whether two files are the same, rather than one replacing the other
+#[cfg(target_family = "unix")]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// Without inodes a rotation is only noticed when the log shrinks
+#[cfg(not(target_family = "unix"))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn rotates_once_a_log_would_grow_past_its_size() {
+        let path = utils::file::test_dir("log-rotate").join("doc.log");
+        let mut log = RotatingFile::open(&path, 10).unwrap();
+
+        log.write_all(b"first\n").unwrap();
+        log.write_all(b"two\n").unwrap();
+        assert_eq!(read(&path), "first\ntwo\n");
+        assert!(!rotated_path(&path, 1).exists());
+
+        // Past 10 bytes the log starts again
+        log.write_all(b"third\n").unwrap();
+        assert_eq!(read(&rotated_path(&path, 1)), "first\ntwo\n");
+        assert_eq!(read(&path), "third\n");
+
+        // A single line longer than the limit still gets written
+        let mut log = RotatingFile::open(&path, 10).unwrap();
+        log.write_all(b"a very long line\n").unwrap();
+        assert_eq!(read(&path), "a very long line\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "third\n");
+    }
+
+    #[test]
+    fn keeps_only_the_newest_rotated_logs() {
+        let path = utils::file::test_dir("log-trim").join("doc.log");
+        let mut log = RotatingFile::open(&path, 3).unwrap();
+        for line in ["1\n", "2\n", "3\n", "4\n", "5\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read(&path), "5\n");
+        assert_eq!(read(&rotated_path(&path, 1)), "4\n");
+        assert_eq!(read(&rotated_path(&path, ROTATED_LOGS)), "3\n");
+        assert!(!rotated_path(&path, ROTATED_LOGS + 1).exists());
+    }
+
+    #[test]
+    fn follows_a_log_across_rotations() {
+        let path = utils::file::test_dir("log-follow").join("doc.log");
+        let mut log = RotatingFile::open(&path, 10).unwrap();
+        log.write_all(b"old\n").unwrap();
+        let mut follower = Follower {
+            path: path.clone(),
+            file: File::open(&path).unwrap(),
+            position: 0,
+        };
+        let mut out = Vec::new();
+        follower.copy_new(&mut out).unwrap();
+
+        // Written to the old file just before it was moved aside
+        log.write_all(b"last\n").unwrap();
+        log.write_all(b"new\n").unwrap();
+        follower.copy_new(&mut out).unwrap();
+        assert_eq!(String::from_utf8_lossy(&out), "old\nlast\nnew\n");
+
+        // Truncated in place
+        fs::write(&path, "a\n").unwrap();
+        follower.copy_new(&mut out).unwrap();
+        assert_eq!(String::from_utf8_lossy(&out), "old\nlast\nnew\na\n");
+    }
+}
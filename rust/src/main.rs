@@ -1,112 +1,479 @@
-mod renderer;
-mod server;
-mod utils;
-mod watcher;
-
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use tracing::info;
+use clap::{Args as _, CommandFactory, FromArgMatches, Parser, Subcommand};
+use tracing::{error, info};
+
+use marv::{config, logs, server, utils, watcher};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Markdown viewer with Mermaid support", long_about = None)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    override_usage = "marv <COMMAND>"
+)]
 struct Args {
-    /// Start the preview server for a markdown file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // The flags below are the deprecated forms of the subcommands, kept so
+    // that existing scripts and editor plugins keep working
+    /// Deprecated: use `marv start`
     #[arg(long, group = "action", required = false, conflicts_with_all = ["stop", "bg_server", "kill_all"])]
     start: bool,
 
-    /// Stop the preview server for a markdown file
+    /// Deprecated: use `marv stop`
     #[arg(long, group = "action", required = false, conflicts_with_all = ["start", "bg_server", "kill_all"])]
     stop: bool,
 
-    /// Kill all running preview servers
+    /// Deprecated: use `marv kill-all`
     #[arg(long, group = "action", required = false, conflicts_with_all = ["start", "stop", "bg_server"])]
     kill_all: bool,
 
     /// Internal use only: run the server in background mode
-    #[arg(long, group = "action", required = false, conflicts_with_all = ["start", "stop", "kill_all"], hide = true)]
+    #[arg(long, group = "action", required = false, conflicts_with_all = ["start", "stop", "kill_all"])]
     bg_server: bool,
 
+    #[command(flatten)]
+    server: ServerArgs,
+
+    #[command(flatten)]
+    browser: BrowserArgs,
+
+    /// Stop the server when this process exits (e.g. the editor's PID)
+    #[arg(long, value_name = "PID")]
+    parent_pid: Option<u32>,
+
     /// Input markdown file path
     #[arg(required = false)]
     input: Option<String>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start a preview server in the background and open it in a browser
+    Start {
+        #[command(flatten)]
+        server: ServerArgs,
+
+        #[command(flatten)]
+        browser: BrowserArgs,
+
+        /// Stop the server when this process exits (e.g. the editor's PID)
+        #[arg(long, value_name = "PID")]
+        parent_pid: Option<u32>,
+
+        /// Markdown or Mermaid file to preview
+        input: String,
+    },
+    /// Stop the preview server for a file
+    Stop {
+        /// File the server previews
+        input: String,
+    },
+    /// Show whether a preview server is running for a file (exits with 1 if not)
+    Status {
+        /// File the server previews
+        input: String,
+    },
+    /// List running preview servers
+    List,
+    /// Stop all running preview servers
+    KillAll,
+    /// Render a file to a standalone HTML page
+    Export {
+        /// Where to write the page (default: standard output)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Markdown or Mermaid file to render
+        input: String,
+    },
+    /// Run a preview server in the foreground until Ctrl+C, e.g. in a container
+    Serve {
+        #[command(flatten)]
+        server: ServerArgs,
+
+        /// Port to serve on (default: the document's port in the configured range)
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// Markdown or Mermaid file to preview
+        input: String,
+    },
+    /// Inspect marv configuration
+    Config {
+        /// Print the effective configuration values
+        #[arg(long)]
+        show: bool,
+
+        /// Markdown file whose project overrides should be applied
+        input: Option<String>,
+    },
+    /// Show the log of a background server
+    Logs {
+        /// Number of lines to show
+        #[arg(short = 'n', long, default_value_t = 50)]
+        lines: usize,
+
+        /// Keep printing lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Markdown file the server previews
+        input: String,
+    },
+}
+
+// Options for how a server serves its document
+#[derive(clap::Args, Debug, Clone, Default)]
+struct ServerArgs {
+    /// Detect file changes by polling (for network and container filesystems)
+    #[arg(long)]
+    poll: bool,
+
+    /// Address to serve on; anything but loopback shares the preview with an access token
+    #[arg(long, value_name = "ADDRESS")]
+    bind: Option<IpAddr>,
+
+    /// Serve the preview over HTTPS
+    #[arg(long)]
+    tls: bool,
+
+    /// Trust the document: render its raw HTML unsanitized and let its scripts run
+    #[arg(long)]
+    trust: bool,
+}
+
+// Options for how a started server is opened
+#[derive(clap::Args, Debug, Clone, Default)]
+struct BrowserArgs {
+    /// Don't open the preview in a browser
+    #[arg(long)]
+    no_browser: bool,
+
+    /// Browser command to open the preview with (default: $BROWSER or the system browser)
+    #[arg(long, value_name = "COMMAND")]
+    browser: Option<String>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    let args = parse_args();
 
-    let args = Args::parse();
+    // Initialize logging; servers log to a file or stderr once their
+    // configuration is known
+    if !args.bg_server && !matches!(args.command, Some(Command::Serve { .. })) {
+        tracing_subscriber::fmt::init();
+    }
 
-    // Handle kill all mode
-    if args.kill_all {
-        info!("Killing all running marv servers");
-        return server::kill_all_servers();
+    // Handle background server mode
+    if args.bg_server {
+        return run_background_server(&args).await;
     }
 
-    // Make sure input is provided for all other actions
-    let input = match args.input {
-        Some(input) => input,
-        None => {
-            return Err(anyhow!(
-                "Input file path is required when not using --kill-all"
-            ))
-        }
+    let command = match args.command {
+        Some(command) => command,
+        None => match deprecated_command(args) {
+            Some(command) => command,
+            None => {
+                Args::command().print_help()?;
+                return Ok(());
+            }
+        },
     };
 
-    // Handle background server mode
-    if args.bg_server {
-        // This is the mode where we actually run the server in the background
-        let input_path = PathBuf::from(&input);
-        if !input_path.exists() {
-            return Err(anyhow!("Input file does not exist: {}", input));
+    match command {
+        Command::Start {
+            server,
+            browser,
+            parent_pid,
+            input,
+        } => {
+            let input_path = document_path(&input)?;
+            let mut config = load_config(&input_path, &server)?;
+            browser.apply(&mut config);
+            let server_dir = utils::file::get_server_info_dir(&config);
+            info!("Starting preview server for {:?}", input_path);
+            server::start_preview_server(&input_path, &server_dir, &config, parent_pid).await
+        }
+        Command::Stop { input } => {
+            let input_path = absolute_path(&input)?;
+            let config = config::Config::load(Some(&input_path))?;
+            let server_dir = utils::file::get_server_info_dir(&config);
+            info!("Stopping preview server for {:?}", input_path);
+            server::stop_preview_server(&input_path, &server_dir).await
+        }
+        Command::Status { input } => {
+            let input_path = absolute_path(&input)?;
+            let config = config::Config::load(Some(&input_path))?;
+            if !server::print_status(&input_path, &utils::file::get_server_info_dir(&config))
+                .await?
+            {
+                std::process::exit(1);
+            }
+            Ok(())
         }
+        Command::List => {
+            let config = config::Config::load(None)?;
+            server::list_servers(&utils::file::get_server_info_dir(&config)).await
+        }
+        Command::KillAll => {
+            info!("Killing all running marv servers");
+            let config = config::Config::load(None)?;
+            let server_dir = utils::file::get_server_info_dir(&config);
+            server::kill_all_servers(&server_dir).await
+        }
+        Command::Export { output, input } => export(&input, output.as_deref()),
+        Command::Serve {
+            server,
+            port,
+            input,
+        } => {
+            let input_path = document_path(&input)?;
+            let config = load_config(&input_path, &server)?;
+            logs::init_stderr_logging(&config)?;
+            server::serve(&input_path, &config, port).await
+        }
+        Command::Config { show, input } => show_config(show, input.as_deref()),
+        Command::Logs {
+            lines,
+            follow,
+            input,
+        } => show_logs(&input, lines, follow),
+    }
+}
 
-        // Get absolute path
-        let input_path = std::fs::canonicalize(&input_path)
-            .context(format!("Failed to get absolute path for {}", input))?;
+// Top-level arguments that are deprecated forms of the subcommands, besides
+// the server and browser options they take
+const LEGACY_ARGS: &[&str] = &[
+    "start",
+    "stop",
+    "kill_all",
+    "bg_server",
+    "parent_pid",
+    "input",
+];
 
-        info!("Running server in background mode for {:?}", input_path);
+// Parse the command line
+fn parse_args() -> Args {
+    Args::from_arg_matches(&command().get_matches()).unwrap_or_else(|e| e.exit())
+}
 
-        // Create directory for server info
-        let server_dir = utils::file::get_server_info_dir()?;
+// The command line interface, with the deprecated top-level arguments hidden
+// so that the help lists the subcommands and the global options
+fn command() -> clap::Command {
+    let options = BrowserArgs::augment_args(ServerArgs::augment_args(clap::Command::new("")));
+    let legacy: Vec<&str> = LEGACY_ARGS
+        .iter()
+        .copied()
+        .chain(options.get_arguments().map(|arg| arg.get_id().as_str()))
+        .collect();
+    Args::command().mut_args(|arg| {
+        let hidden = legacy.contains(&arg.get_id().as_str());
+        arg.hide(hidden)
+    })
+}
 
-        // Run the actual server
-        return server::run_server(&input_path, &server_dir).await;
+// The subcommand a deprecated flag stands for, with a warning to switch
+fn deprecated_command(args: Args) -> Option<Command> {
+    let (flag, command) = if args.kill_all {
+        ("--kill-all", Command::KillAll)
+    } else if args.stop {
+        ("--stop", Command::Stop { input: args.input? })
+    } else {
+        let flag = if args.start { "--start" } else { "<FILE>" };
+        let command = Command::Start {
+            server: args.server,
+            browser: args.browser,
+            parent_pid: args.parent_pid,
+            input: args.input?,
+        };
+        (flag, command)
+    };
+
+    let name = match &command {
+        Command::KillAll => "kill-all",
+        Command::Stop { .. } => "stop",
+        _ => "start",
+    };
+    eprintln!(
+        "warning: 'marv {}' is deprecated, use 'marv {}' instead",
+        flag, name
+    );
+    Some(command)
+}
+
+// Run the server started by 'marv start', whose record is already written
+async fn run_background_server(args: &Args) -> Result<()> {
+    let input = args
+        .input
+        .as_deref()
+        .ok_or_else(|| anyhow!("Input file path is required"))?;
+    let input_path = PathBuf::from(input);
+    if !input_path.exists() {
+        return Err(anyhow!("Input file does not exist: {}", input));
     }
 
-    // Get absolute path for consistency
-    let input_path = std::fs::canonicalize(PathBuf::from(&input))
+    // Get absolute path
+    let input_path = std::fs::canonicalize(&input_path)
         .context(format!("Failed to get absolute path for {}", input))?;
 
-    // Create directory for server info if it doesn't exist
-    let server_dir = utils::file::get_server_info_dir()?;
-    info!("Server info directory: {:?}", server_dir);
+    // Create directory for server info
+    let config = load_config(&input_path, &args.server)?;
+    let server_dir = utils::file::get_server_info_dir(&config);
+    logs::init_file_logging(&logs::log_path(&config, &input_path), &config)?;
+
+    info!("Running server in background mode for {:?}", input_path);
+
+    // Run the actual server, noting why it stopped if it failed
+    let result = server::run_server(&input_path, &server_dir, &config, args.parent_pid).await;
+    if let Err(e) = &result {
+        error!("Background server failed: {:#}", e);
+    }
+    result
+}
+
+// Get absolute path for consistency
+fn absolute_path(input: &str) -> Result<PathBuf> {
+    std::fs::canonicalize(PathBuf::from(input))
+        .context(format!("Failed to get absolute path for {}", input))
+}
+
+// The absolute path of a document to render, which must be markdown or mermaid
+fn document_path(input: &str) -> Result<PathBuf> {
+    let input_path = absolute_path(input)?;
+
+    let extension = input_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+
+    if extension != "md" && extension != "markdown" && extension != "mmd" && extension != "mermaid"
+    {
+        return Err(anyhow!("Input file must be a markdown or mermaid file with .md, .markdown, .mmd or .mermaid extension"));
+    }
+
+    if !input_path.exists() {
+        return Err(anyhow!("Input file does not exist: {:?}", input_path));
+    }
 
-    // Ensure input file is markdown if starting a server
-    if !args.stop {
-        let extension = input_path
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("");
+    Ok(input_path)
+}
 
-        if extension != "md" && extension != "markdown" && extension != "mmd" {
-            return Err(anyhow!("Input file must be a markdown or mermaid file with .md, .markdown, or .mmd extension"));
+// Load the configuration for a document, applying command line overrides
+fn load_config(input_path: &Path, server: &ServerArgs) -> Result<config::Config> {
+    let mut config = config::Config::load(Some(input_path))?;
+    if server.poll {
+        config.watcher = config::WatcherMode::Poll;
+    }
+    if let Some(bind) = server.bind {
+        config.bind = bind;
+    }
+    if server.tls {
+        config.tls = true;
+    }
+    if server.trust {
+        config.trust = true;
+    }
+    Ok(config)
+}
+
+impl BrowserArgs {
+    fn apply(&self, config: &mut config::Config) {
+        if self.no_browser {
+            config.open_browser = false;
+        }
+        if let Some(browser) = &self.browser {
+            config.browser = Some(browser.clone());
         }
+    }
+}
+
+// Render a document to a page that works without a server, e.g. to share or
+// archive it. The page keeps its own scripts for highlighting and diagrams.
+fn export(input: &str, output: Option<&Path>) -> Result<()> {
+    let input_path = document_path(input)?;
+    let config = config::Config::load(Some(&input_path))?;
+    let state = watcher::render_file(&input_path, &config)?;
 
-        if !input_path.exists() {
-            return Err(anyhow!("Input file does not exist: {:?}", input_path));
+    match output {
+        Some(output) => {
+            std::fs::write(output, &state.html).context(format!("Failed to write {:?}", output))?;
+            println!("Exported {:?} to {:?}", input_path, output);
         }
+        None => print!("{}", state.html),
     }
+    Ok(())
+}
 
-    if args.stop {
-        info!("Stopping preview server for {:?}", input_path);
-        server::stop_preview_server(&input_path, &server_dir)
-    } else {
-        // Default to start mode
-        info!("Starting preview server for {:?}", input_path);
-        server::start_preview_server(&input_path, &server_dir).await
+// Print the log of the server for a document
+fn show_logs(input: &str, lines: usize, follow: bool) -> Result<()> {
+    let input_path = absolute_path(input)?;
+    let config = config::Config::load(Some(&input_path))?;
+    let log_path = logs::log_path(&config, &input_path);
+    if !log_path.exists() {
+        return Err(anyhow!(
+            "No log for {:?}; one is written once a preview server starts for it",
+            input_path
+        ));
+    }
+    logs::tail(&log_path, lines, follow)
+}
+
+// Print where configuration is read from, and optionally the effective values
+fn show_config(show: bool, input: Option<&str>) -> Result<()> {
+    let input_path = match input {
+        Some(input) => Some(absolute_path(input)?),
+        None => None,
+    };
+    let config = config::Config::load(input_path.as_deref())?;
+
+    if let Some(path) = config::global_config_path() {
+        println!("# Global config: {}", path.display());
+    }
+    println!("# Project config: .marv.toml in the document's directory or any parent");
+    println!("# Environment: MARV_<KEY> (e.g. MARV_BIND, MARV_PORT_MIN)");
+    for source in &config.sources {
+        println!("# Loaded: {}", source);
+    }
+
+    if show {
+        println!();
+        print!("{}", config.to_toml()?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_only_subcommands_and_global_options_in_the_help() {
+        let help = command().render_help().to_string();
+        for listed in ["start", "kill-all", "logs", "--help", "--version"] {
+            assert!(help.contains(listed), "{} missing from:\n{}", listed, help);
+        }
+        for hidden in [
+            "--start",
+            "--kill-all",
+            "--bg-server",
+            "--poll",
+            "--no-browser",
+            "<INPUT>",
+        ] {
+            assert!(!help.contains(hidden), "{} listed in:\n{}", hidden, help);
+        }
+
+        // The deprecated flags still parse
+        let matches = command()
+            .try_get_matches_from(["marv", "--stop", "doc.md"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert!(args.stop);
+        assert_eq!(args.input.as_deref(), Some("doc.md"));
     }
 }
@@ -0,0 +1,122 @@
+// Messages exchanged over the preview WebSocket (/ws). Every message is a
+// JSON object whose "type" field names the message:
+//
+// Server to browser:
+//   {"type":"hello","protocol":2,"version":"0.1.0","doc_id":"<md5 of path>",
+//    "render_id":"<id>"}
+//       sent once when the connection opens; a page whose marv-render-id
+//       meta tag differs from render_id is showing stale content
+//   {"type":"content","render_id":"<id>","deleted":false}
+//       the document changed; reload, or show that the file is missing
+//   {"type":"error","severity":"error","message":"...","line":12}
+//       the latest change could not be shown ("warning" for problems with
+//       content that was shown); line is 1-based and may be null
+//   {"type":"scroll","line":40}   another tab scrolled to a source line
+//   {"type":"goto","line":40}     jump to and highlight a source line
+//   {"type":"focus"}              the preview was opened again; come to the front
+//   {"type":"bye","reason":"..."}
+//       the server is stopping on purpose; don't report a lost connection,
+//       but keep trying to reconnect slowly (the page tries every ten
+//       seconds) in case it is started again on the same port
+//
+// Browser to server:
+//   {"type":"hello","protocol":2}  the client's protocol version
+//   {"type":"scroll","line":40}    relayed to the other tabs
+//   {"type":"goto","line":40}      relayed to the other tabs
+//
+// Source lines refer to data-source-line attributes in the rendered page.
+// New message types and fields may be added within a protocol version, so
+// both sides ignore what they don't recognise. Incompatible changes bump
+// PROTOCOL_VERSION; a page whose client speaks another version reloads to
+// pick up the server's script.
+
+use serde::{Deserialize, Serialize};
+
+use crate::watcher::RenderError;
+
+// Version of the message schema. 2 renamed the content messages' revision
+// to render_id.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// How serious a reported problem is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    // The latest change could not be shown
+    Error,
+    // The content was shown, but with a problem
+    Warning,
+}
+
+// Messages sent by the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    Hello {
+        protocol: u32,
+        version: String,
+        doc_id: String,
+        render_id: String,
+    },
+    Content {
+        render_id: String,
+        deleted: bool,
+    },
+    Error {
+        severity: Severity,
+        message: String,
+        line: Option<usize>,
+    },
+    Scroll {
+        line: usize,
+    },
+    Goto {
+        line: usize,
+    },
+    Focus,
+    Bye {
+        reason: String,
+    },
+}
+
+// Messages sent by the browser
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Hello { protocol: u32 },
+    Scroll { line: usize },
+    Goto { line: usize },
+}
+
+impl ServerMessage {
+    // The greeting for a new connection
+    pub fn hello(doc_id: &str, render_id: &str) -> ServerMessage {
+        ServerMessage::Hello {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            doc_id: doc_id.to_string(),
+            render_id: render_id.to_string(),
+        }
+    }
+
+    // Report a read or render problem
+    pub fn error(error: &RenderError, severity: Severity) -> ServerMessage {
+        ServerMessage::Error {
+            severity,
+            message: error.message.clone(),
+            line: error.line,
+        }
+    }
+
+    // Encode the message as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server messages always serialize")
+    }
+}
+
+impl ClientMessage {
+    // Decode a message from the browser, ignoring unknown types
+    pub fn parse(text: &str) -> Option<ClientMessage> {
+        serde_json::from_str(text).ok()
+    }
+}
@@ -1,29 +1,535 @@
-use maud::{html, PreEscaped, DOCTYPE};
-use pulldown_cmark::{html, Event, Options, Parser};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use pulldown_cmark::{html, Event, HeadingLevel, Options, Parser, Tag};
+use rand::Rng;
 use syntect::highlighting::ThemeSet;
 use syntect::html::highlighted_html_for_string;
 use syntect::parsing::SyntaxSet;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::diagrams;
+use crate::includes;
+use crate::utils;
+
+// Syntect theme used when the configured one does not exist
+const DEFAULT_SYNTAX_THEME: &str = "base16-ocean.dark";
+
+// Ids of the page's own elements, which the document's headings and diagrams
+// can't take
+pub(crate) const PAGE_IDS: &[&str] = &["content", "file-path", "marv-status", "marv-errors"];
+
+// Built-in page styles
+const BASE_STYLES: &str = "body {
+    font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, Helvetica, Arial, sans-serif;
+    line-height: 1.6;
+    max-width: 800px;
+    margin: 0 auto;
+    padding: 2rem;
+    background-color: #1e1e1e;
+    color: #e0e0e0;
+}
+pre, code {
+    background-color: #2d2d2d;
+    border-radius: 3px;
+    padding: 0.2em 0.4em;
+    font-family: monospace;
+    color: #d4d4d4;
+}
+pre code {
+    padding: 0;
+}
+pre.syntax-highlight {
+    padding: 1em;
+    overflow: auto;
+}
+/* Syntax highlighting colors */
+.syntax-highlight .comment { color: #65737e; }
+.syntax-highlight .string { color: #a3be8c; }
+.syntax-highlight .keyword { color: #b48ead; }
+.syntax-highlight .function { color: #8fa1b3; }
+.syntax-highlight .number { color: #d08770; }
+.syntax-highlight .constant { color: #d08770; }
+.syntax-highlight .type { color: #ebcb8b; }
+.syntax-highlight .tag { color: #bf616a; }
+.syntax-highlight .attribute { color: #d08770; }
+.syntax-highlight .title { color: #8fa1b3; font-weight: bold; }
+.syntax-highlight .attribute-value { color: #a3be8c; }
+table {
+    border-collapse: collapse;
+    width: 100%;
+}
+table, th, td {
+    border: 1px solid #444;
+}
+th, td {
+    padding: 8px;
+}
+tr:nth-child(even) {
+    background-color: #2a2a2a;
+}
+img {
+    max-width: 100%;
+}
+blockquote {
+    border-left: 4px solid #444;
+    padding-left: 1rem;
+    margin-left: 0;
+    color: #aaa;
+}
+a {
+    color: #58a6ff;
+}
+h1, h2, h3, h4, h5, h6 {
+    margin-top: 1.5em;
+    margin-bottom: 0.5em;
+}
+p, ul, ol {
+    margin: 1em 0;
+}
+/* File path header styling */
+#file-path {
+    margin-bottom: 2em;
+}
+#file-path h1 {
+    font-size: 1.2em;
+    margin-top: 0.5em;
+    margin-bottom: 0.5em;
+    word-break: break-all;
+    overflow-wrap: break-word;
+}
+.update-indicator {
+    position: fixed;
+    bottom: 10px;
+    right: 10px;
+    background-color: #2e6930;
+    color: #e0e0e0;
+    padding: 5px 10px;
+    border-radius: 5px;
+    font-size: 12px;
+    opacity: 0;
+    transition: opacity 0.3s;
+}
+.status-banner {
+    position: fixed;
+    top: 0;
+    left: 0;
+    right: 0;
+    background-color: #6e3b1e;
+    color: #e0e0e0;
+    padding: 8px 16px;
+    font-size: 14px;
+    text-align: center;
+    opacity: 0;
+    transition: opacity 0.3s;
+}
+.marv-error {
+    border-left: 4px solid #bf616a;
+    background-color: #3b2228;
+    padding: 0.5em 1em;
+    margin: 1em 0;
+    font-family: monospace;
+}
+.marv-error-overlay {
+    position: fixed;
+    top: 10px;
+    left: 50%;
+    transform: translateX(-50%);
+    width: min(90%, 800px);
+    z-index: 1000;
+}
+.marv-error-entry {
+    background-color: #3b2228;
+    border-left: 4px solid #bf616a;
+    color: #e0e0e0;
+    padding: 8px 12px;
+    margin-bottom: 6px;
+    box-shadow: 0 2px 8px rgba(0, 0, 0, 0.5);
+}
+.marv-error-entry pre {
+    margin: 0;
+    background: none;
+    padding: 0;
+    white-space: pre-wrap;
+}
+.marv-error-entry button {
+    float: right;
+    background: none;
+    border: none;
+    color: #e0e0e0;
+    font-size: 16px;
+    cursor: pointer;
+}
+.diagram {
+    text-align: center;
+    margin: 1em 0;
+}
+.diagram svg {
+    max-width: 100%;
+    height: auto;
+}
+.source-highlight {
+    outline: 2px solid #8fa1b3;
+    outline-offset: 4px;
+}
+.visible {
+    opacity: 1;
+}";
+
+// Styles for standalone diagram pages, applied on top of the built-in styles
+const DIAGRAM_STYLES: &str = "body {
+    max-width: none;
+    height: 100vh;
+    box-sizing: border-box;
+    display: flex;
+    flex-direction: column;
+}
+#file-path {
+    margin-bottom: 1em;
+}
+.diagram-toolbar {
+    display: flex;
+    gap: 0.5em;
+    margin-bottom: 1em;
+}
+.diagram-toolbar button {
+    background-color: #2d2d2d;
+    color: #e0e0e0;
+    border: 1px solid #444;
+    border-radius: 3px;
+    padding: 0.3em 0.8em;
+    cursor: pointer;
+}
+.diagram-toolbar button:hover {
+    background-color: #3a3a3a;
+}
+#diagram-viewport {
+    flex: 1;
+    overflow: hidden;
+    border: 1px solid #444;
+    border-radius: 3px;
+    cursor: grab;
+    touch-action: none;
+}
+#diagram-viewport.panning {
+    cursor: grabbing;
+}
+#diagram {
+    transform-origin: 0 0;
+    display: inline-block;
+    margin: 0;
+    padding: 0;
+    background: none;
+}";
+
+// Zoom, pan and download controls for standalone diagram pages
+const DIAGRAM_SCRIPT: &str = "document.addEventListener('DOMContentLoaded', async function() {
+    const viewport = document.getElementById('diagram-viewport');
+    const diagram = document.getElementById('diagram');
+    let scale = 1, x = 0, y = 0;
+
+    function apply() {
+        diagram.style.transform = `translate(${x}px, ${y}px) scale(${scale})`;
+    }
+
+    function zoomAt(factor, cx, cy) {
+        const next = Math.min(Math.max(scale * factor, 0.1), 20);
+        x = cx - (cx - x) * (next / scale);
+        y = cy - (cy - y) * (next / scale);
+        scale = next;
+        apply();
+    }
+
+    function zoomCentre(factor) {
+        zoomAt(factor, viewport.clientWidth / 2, viewport.clientHeight / 2);
+    }
+
+    function fit() {
+        const svg = diagram.querySelector('svg');
+        if (!svg) return;
+        scale = 1; x = 0; y = 0;
+        apply();
+        const box = svg.getBoundingClientRect();
+        scale = Math.min(viewport.clientWidth / box.width, viewport.clientHeight / box.height, 1);
+        x = (viewport.clientWidth - box.width * scale) / 2;
+        y = (viewport.clientHeight - box.height * scale) / 2;
+        apply();
+    }
+
+    function download(blob, extension) {
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(blob);
+        link.download = diagram.dataset.name + '.' + extension;
+        link.click();
+        setTimeout(() => URL.revokeObjectURL(link.href), 1000);
+    }
+
+    function svgSource() {
+        const svg = diagram.querySelector('svg');
+        return svg ? new XMLSerializer().serializeToString(svg) : null;
+    }
+
+    function downloadSvg() {
+        const source = svgSource();
+        if (source) download(new Blob([source], { type: 'image/svg+xml' }), 'svg');
+    }
+
+    function downloadPng() {
+        const svg = diagram.querySelector('svg');
+        const source = svgSource();
+        if (!svg || !source) return;
+        const box = svg.viewBox.baseVal;
+        const rect = svg.getBoundingClientRect();
+        const width = box && box.width ? box.width : rect.width;
+        const height = box && box.height ? box.height : rect.height;
+        const image = new Image();
+        image.onload = function() {
+            const ratio = 2;
+            const canvas = document.createElement('canvas');
+            canvas.width = width * ratio;
+            canvas.height = height * ratio;
+            const context = canvas.getContext('2d');
+            context.fillStyle = getComputedStyle(document.body).backgroundColor;
+            context.fillRect(0, 0, canvas.width, canvas.height);
+            context.drawImage(image, 0, 0, canvas.width, canvas.height);
+            canvas.toBlob(blob => download(blob, 'png'));
+        };
+        image.src = 'data:image/svg+xml;base64,' + btoa(unescape(encodeURIComponent(source)));
+    }
+
+    viewport.addEventListener('wheel', function(event) {
+        event.preventDefault();
+        const rect = viewport.getBoundingClientRect();
+        zoomAt(event.deltaY < 0 ? 1.1 : 1 / 1.1, event.clientX - rect.left, event.clientY - rect.top);
+    }, { passive: false });
+
+    let drag = null;
+    viewport.addEventListener('pointerdown', function(event) {
+        drag = { x: event.clientX - x, y: event.clientY - y };
+        viewport.setPointerCapture(event.pointerId);
+        viewport.classList.add('panning');
+    });
+    viewport.addEventListener('pointermove', function(event) {
+        if (!drag) return;
+        x = event.clientX - drag.x;
+        y = event.clientY - drag.y;
+        apply();
+    });
+    viewport.addEventListener('pointerup', function() {
+        drag = null;
+        viewport.classList.remove('panning');
+    });
 
-// Convert markdown to HTML
-pub fn markdown_to_html(markdown: &str, filename: &str) -> String {
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_TABLES);
-    options.insert(Options::ENABLE_FOOTNOTES);
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TASKLISTS);
+    document.getElementById('zoom-in').addEventListener('click', () => zoomCentre(1.25));
+    document.getElementById('zoom-out').addEventListener('click', () => zoomCentre(0.8));
+    document.getElementById('zoom-fit').addEventListener('click', fit);
+    document.getElementById('download-svg').addEventListener('click', downloadSvg);
+    document.getElementById('download-png').addEventListener('click', downloadPng);
+
+    await mermaid.run({ nodes: [diagram], suppressErrors: true });
+    fit();
+});";
+
+// Dismissible error overlay, shared by the markdown and diagram pages.
+// marvShowError(source, message, line) adds an entry; repeated errors are
+// shown once.
+const ERROR_SCRIPT: &str = "window.marvShowError = function(source, message, line) {
+    let overlay = document.getElementById('marv-errors');
+    if (!overlay) {
+        overlay = document.createElement('div');
+        overlay.id = 'marv-errors';
+        overlay.className = 'marv-error-overlay';
+        document.body.appendChild(overlay);
+    }
+    const text = source + (line ? ' (line ' + line + ')' : '') + ': ' + message;
+    for (const entry of overlay.children) {
+        if (entry.dataset.text === text) return;
+    }
+    const entry = document.createElement('div');
+    entry.className = 'marv-error-entry';
+    entry.dataset.text = text;
+    const label = document.createElement('pre');
+    label.textContent = text;
+    const close = document.createElement('button');
+    close.textContent = '\u{00d7}';
+    close.title = 'Dismiss';
+    close.addEventListener('click', () => entry.remove());
+    entry.appendChild(close);
+    entry.appendChild(label);
+    overlay.appendChild(entry);
+};
+
+// Mermaid reports syntax errors here rather than throwing
+window.marvMermaidError = function(error, hash) {
+    const message = typeof error === 'string' ? error : (error && (error.str || error.message)) || String(error);
+    const line = hash && hash.loc ? hash.loc.first_line : (hash && hash.line !== undefined ? hash.line + 1 : null);
+    window.marvShowError('Mermaid diagram', message.trim(), line);
+};";
+
+// User customisation of the rendered page
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    // Mermaid theme
+    pub theme: String,
+    // Syntect theme for code blocks
+    pub syntax_theme: String,
+    // Where the Mermaid script is loaded from
+    pub mermaid_url: String,
+    // CSS max-width of the page body
+    pub max_width: String,
+    // Stylesheets appended after the built-in styles, in order
+    pub stylesheets: Vec<String>,
+    // Full page template replacing the built-in skeleton
+    pub template: Option<String>,
+    // How deeply markdown includes may nest
+    pub include_depth: usize,
+    // Directory included files must be in, instead of the document's
+    pub include_root: Option<PathBuf>,
+    // Encoding for included files that aren't UTF-8
+    pub encoding: &'static encoding_rs::Encoding,
+    // Local tools for graphviz and plantuml blocks
+    pub diagram_tools: diagrams::DiagramTools,
+    // Strip raw HTML down to an allowlist of tags and attributes
+    pub sanitize: bool,
+    // Marks the page's own scripts as allowed by its Content-Security-Policy
+    pub script_nonce: String,
+}
+
+impl RenderOptions {
+    // Build the renderer options for a document from the configuration
+    pub fn load(config: &Config, input_path: &Path) -> RenderOptions {
+        RenderOptions {
+            theme: config.theme.clone(),
+            syntax_theme: config.syntax_theme.clone(),
+            mermaid_url: config.mermaid_url.clone(),
+            max_width: config.max_width.clone(),
+            stylesheets: utils::file::read_user_stylesheets(config, input_path),
+            template: utils::file::read_user_template(config),
+            include_depth: config.include_depth,
+            include_root: config.include_root.clone(),
+            encoding: config.fallback_encoding(),
+            diagram_tools: diagrams::DiagramTools::load(config),
+            sanitize: config.sanitizes_html(),
+            script_nonce: new_nonce(),
+        }
+    }
+}
+
+// A random value for a script nonce, new for every render
+fn new_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+// Content-Security-Policy for a rendered page. Only the page's own scripts
+// (carrying the nonce) and the Mermaid library run, so scripts and event
+// handlers in the document's raw HTML don't.
+pub fn content_security_policy(nonce: &str, mermaid_url: &str) -> String {
+    let mermaid_source =
+        if mermaid_url.starts_with("https://") || mermaid_url.starts_with("http://") {
+            mermaid_url
+        } else {
+            "'self'"
+        };
+    format!(
+        "default-src 'self'; script-src 'nonce-{}' {}; style-src * 'unsafe-inline'; \
+         img-src * data: blob:; font-src * data:; media-src * data: blob:; \
+         connect-src 'self' ws: wss:; object-src 'none'; base-uri 'none'",
+        nonce, mermaid_source
+    )
+}
+
+// A rendered page and the local files it depends on
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderedPage {
+    pub html: String,
+    // Local files referenced by the page (images, linked stylesheets)
+    pub dependencies: BTreeSet<PathBuf>,
+}
+
+// A heading collected for the table of contents
+struct Heading {
+    level: HeadingLevel,
+    text: String,
+    id: String,
+}
+
+// Convert markdown to HTML. Relative references are resolved against the
+// directory of `filename`, which should be the document's absolute path.
+pub fn markdown_to_html(markdown: &str, filename: &str, options: &RenderOptions) -> RenderedPage {
+    let mut parser_options = Options::empty();
+    parser_options.insert(Options::ENABLE_TABLES);
+    parser_options.insert(Options::ENABLE_FOOTNOTES);
+    parser_options.insert(Options::ENABLE_STRIKETHROUGH);
+    parser_options.insert(Options::ENABLE_TASKLISTS);
+
+    // Splice in included files before parsing
+    let expanded = includes::expand_includes(
+        markdown,
+        Path::new(filename),
+        options.include_root.as_deref(),
+        options.include_depth,
+        options.encoding,
+    );
 
     // Custom handling to preserve code blocks
-    let parser = Parser::new_ext(markdown, options);
+    let parser = Parser::new_ext(&expanded.markdown, parser_options);
     let mut html_output = String::new();
 
     // Use a custom renderer to handle code blocks properly
     let mut events: Vec<Event> = Vec::new();
-    for event in parser {
+    let mut offsets: Vec<usize> = Vec::new();
+    for (event, range) in parser.into_offset_iter() {
         events.push(event);
+        offsets.push(range.start);
     }
 
+    // Record the local files the page refers to
+    let base_dir = Path::new(filename).parent().unwrap_or(Path::new("."));
+    let mut dependencies = collect_dependencies(&events, base_dir);
+    dependencies.extend(expanded.dependencies.iter().cloned());
+
+    // Give every heading an anchor so the table of contents can link to it
+    let headings = collect_headings(&events);
+    let mut heading_ids = headings.iter().map(|heading| heading.id.as_str());
+    let events = events.into_iter().map(|event| match event {
+        Event::Start(Tag::Heading(level, None, classes)) => {
+            Event::Start(Tag::Heading(level, heading_ids.next(), classes))
+        }
+        event => event,
+    });
+
+    // Mark where each top-level block starts in the source
+    let events = with_source_lines(events, &offsets, &expanded);
+
     // Process the events
     html::push_html(&mut html_output, events.into_iter());
+    if options.sanitize {
+        html_output = sanitize_html(&html_output, &headings);
+    }
+    let html_output = diagrams::render_diagram_blocks(&html_output, &options.diagram_tools);
+    let content = process_mermaid_diagrams(&html_output, &options.syntax_theme);
+
+    if let Some(template) = &options.template {
+        let html = apply_template(
+            template,
+            &[
+                ("title", html_escape::encode_text(filename).to_string()),
+                ("styles", page_styles(options, None).into_string()),
+                ("scripts", page_scripts(options).into_string()),
+                ("nonce", options.script_nonce.clone()),
+                ("toc", table_of_contents(&headings).into_string()),
+                ("content", content),
+            ],
+        );
+        return RenderedPage { html, dependencies };
+    }
 
     let page = html! {
     (DOCTYPE)
@@ -32,111 +538,8 @@ pub fn markdown_to_html(markdown: &str, filename: &str) -> String {
             meta charset="utf-8";
             meta name="viewport" content="width=device-width, initial-scale=1.0, maximum-scale=1.0, user-scalable=no";
             title { "marv" }
-            style {
-                "body {
-                        font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, Helvetica, Arial, sans-serif;
-                        line-height: 1.6;
-                        max-width: 800px;
-                        margin: 0 auto;
-                        padding: 2rem;
-                        background-color: #1e1e1e;
-                        color: #e0e0e0;
-                    }
-                    pre, code {
-                        background-color: #2d2d2d;
-                        border-radius: 3px;
-                        padding: 0.2em 0.4em;
-                        font-family: monospace;
-                        color: #d4d4d4;
-                    }
-                    pre code {
-                        padding: 0;
-                    }
-                    pre.syntax-highlight {
-                        padding: 1em;
-                        overflow: auto;
-                    }
-                    /* Syntax highlighting colors */
-                    .syntax-highlight .comment { color: #65737e; }
-                    .syntax-highlight .string { color: #a3be8c; }
-                    .syntax-highlight .keyword { color: #b48ead; }
-                    .syntax-highlight .function { color: #8fa1b3; }
-                    .syntax-highlight .number { color: #d08770; }
-                    .syntax-highlight .constant { color: #d08770; }
-                    .syntax-highlight .type { color: #ebcb8b; }
-                    .syntax-highlight .tag { color: #bf616a; }
-                    .syntax-highlight .attribute { color: #d08770; }
-                    .syntax-highlight .title { color: #8fa1b3; font-weight: bold; }
-                    .syntax-highlight .attribute-value { color: #a3be8c; }
-                    table {
-                        border-collapse: collapse;
-                        width: 100%;
-                    }
-                    table, th, td {
-                        border: 1px solid #444;
-                    }
-                    th, td {
-                        padding: 8px;
-                    }
-                    tr:nth-child(even) {
-                        background-color: #2a2a2a;
-                    }
-                    img {
-                        max-width: 100%;
-                    }
-                    blockquote {
-                        border-left: 4px solid #444;
-                        padding-left: 1rem;
-                        margin-left: 0;
-                        color: #aaa;
-                    }
-                    a {
-                        color: #58a6ff;
-                    }
-                    h1, h2, h3, h4, h5, h6 {
-                        margin-top: 1.5em;
-                        margin-bottom: 0.5em;
-                    }
-                    p, ul, ol {
-                        margin: 1em 0;
-                    }
-                    /* File path header styling */
-                    #file-path {
-                        margin-bottom: 2em;
-                    }
-                    #file-path h1 {
-                        font-size: 1.2em;
-                        margin-top: 0.5em;
-                        margin-bottom: 0.5em;
-                        word-break: break-all;
-                        overflow-wrap: break-word;
-                    }
-                    .update-indicator {
-                        position: fixed;
-                        bottom: 10px;
-                        right: 10px;
-                        background-color: #2e6930;
-                        color: #e0e0e0;
-                        padding: 5px 10px;
-                        border-radius: 5px;
-                        font-size: 12px;
-                        opacity: 0;
-                        transition: opacity 0.3s;
-                    }
-                    .visible {
-                        opacity: 1;
-                    }"
-            }
-            // Mermaid script
-            script src="https://cdn.jsdelivr.net/npm/mermaid@10/dist/mermaid.min.js" {}
-            script {
-                "document.addEventListener('DOMContentLoaded', function() {
-                        mermaid.initialize({
-                            startOnLoad: true,
-                            theme: 'dark'
-                        });
-                    });"
-            }
+            (page_styles(options, None))
+            (page_scripts(options))
         }
         body {
             // Add filepath header
@@ -146,7 +549,7 @@ pub fn markdown_to_html(markdown: &str, filename: &str) -> String {
             }
 
             div id="content" {
-                (PreEscaped(process_mermaid_diagrams(&html_output)))
+                (PreEscaped(content))
             }
 
             /*
@@ -182,24 +585,362 @@ pub fn markdown_to_html(markdown: &str, filename: &str) -> String {
         }
     };
 
-    page.into_string()
+    RenderedPage {
+        html: page.into_string(),
+        dependencies,
+    }
+}
+
+// Render a mermaid file as a single full-page diagram
+pub fn mermaid_to_html(source: &str, filename: &str, options: &RenderOptions) -> RenderedPage {
+    let name = Path::new(filename)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "diagram".to_string());
+    let init = format!(
+        "mermaid.parseError = window.marvMermaidError;
+        mermaid.initialize({{ startOnLoad: false, theme: {} }});",
+        script_string(&options.theme)
+    );
+
+    let page = html! {
+    (DOCTYPE)
+    html {
+        head {
+            meta charset="utf-8";
+            meta name="viewport" content="width=device-width, initial-scale=1.0";
+            title { (name) }
+            (page_styles(options, Some(DIAGRAM_STYLES)))
+            script nonce=(options.script_nonce) src=(options.mermaid_url) {}
+            script nonce=(options.script_nonce) { (PreEscaped(ERROR_SCRIPT)) }
+            script nonce=(options.script_nonce) { (PreEscaped(init)) }
+            script nonce=(options.script_nonce) { (PreEscaped(DIAGRAM_SCRIPT)) }
+        }
+        body {
+            header id="file-path" {
+                h1 { (filename) }
+                hr;
+            }
+
+            div class="diagram-toolbar" {
+                button id="zoom-in" title="Zoom in" { "+" }
+                button id="zoom-out" title="Zoom out" { "\u{2212}" }
+                button id="zoom-fit" title="Fit to window" { "Fit" }
+                button id="download-svg" { "Download SVG" }
+                button id="download-png" { "Download PNG" }
+            }
+
+            div id="diagram-viewport" {
+                pre id="diagram" class="mermaid" data-name=(name) { (source) }
+            }
+        }
+    }
+    };
+
+    RenderedPage {
+        html: page.into_string(),
+        dependencies: BTreeSet::new(),
+    }
+}
+
+// Whether a file holds a mermaid diagram rather than markdown
+pub fn is_mermaid_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("mmd") | Some("mermaid")
+    )
+}
+
+// Built-in styles followed by any page-specific and user stylesheets
+fn page_styles(options: &RenderOptions, page_specific: Option<&str>) -> Markup {
+    html! {
+        style { (PreEscaped(BASE_STYLES)) }
+        style { (style_content(&format!("body {{ max-width: {}; }}", options.max_width))) }
+        @if let Some(page_specific) = page_specific {
+            style { (PreEscaped(page_specific)) }
+        }
+        @for stylesheet in &options.stylesheets {
+            style { (style_content(stylesheet)) }
+        }
+    }
+}
+
+// Stylesheet content for a <style> element. A "</style" in it would end the
+// element early and let the rest be parsed as HTML, so it is escaped.
+fn style_content(css: &str) -> PreEscaped<String> {
+    static STYLE_END: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r"(?i)</(style)").expect("valid style end pattern"));
+    PreEscaped(STYLE_END.replace_all(css, r"<\/$1").into_owned())
+}
+
+// A string as a JavaScript literal that can't end the script element it is in
+fn script_string(value: &str) -> String {
+    serde_json::to_string(value)
+        .unwrap_or_default()
+        .replace("</", "<\\/")
+}
+
+// Mermaid script
+fn page_scripts(options: &RenderOptions) -> Markup {
+    let init = format!(
+        "document.addEventListener('DOMContentLoaded', function() {{
+                mermaid.parseError = window.marvMermaidError;
+                mermaid.initialize({{
+                    startOnLoad: true,
+                    theme: {}
+                }});
+            }});",
+        script_string(&options.theme)
+    );
+
+    html! {
+        script nonce=(options.script_nonce) src=(options.mermaid_url) {}
+        script nonce=(options.script_nonce) { (PreEscaped(ERROR_SCRIPT)) }
+        script nonce=(options.script_nonce) { (PreEscaped(init)) }
+    }
+}
+
+// Insert an empty marker before every top-level block recording the source
+// line it starts on, so the browser can keep its place across reloads and
+// scroll other tabs to the same block. Blocks from included files are
+// marked with the line of their include directive.
+fn with_source_lines<'a>(
+    events: impl Iterator<Item = Event<'a>>,
+    offsets: &[usize],
+    expanded: &includes::Expanded,
+) -> Vec<Event<'a>> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(expanded.markdown.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+
+    let mut output = Vec::new();
+    let mut depth = 0;
+    for (event, offset) in events.zip(offsets) {
+        let top_level_block = depth == 0 && matches!(event, Event::Start(_) | Event::Rule);
+        if top_level_block {
+            let line = line_starts.partition_point(|start| *start <= *offset);
+            let line = expanded.source_lines.get(line - 1).copied().unwrap_or(line);
+            output.push(Event::Html(
+                format!(
+                    "<div class=\"source-line\" data-source-line=\"{}\"></div>\n",
+                    line
+                )
+                .into(),
+            ));
+        }
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => (),
+        }
+        output.push(event);
+    }
+    output
+}
+
+// Collect local files referenced by images and by raw HTML img/link tags
+fn collect_dependencies(events: &[Event], base_dir: &Path) -> BTreeSet<PathBuf> {
+    static HTML_REF: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(
+            r#"(?i)<(?:img|link|source)\b[^>]*?\s(?:src|href)\s*=\s*["']([^"']+)["']"#,
+        )
+        .expect("valid HTML reference pattern")
+    });
+
+    let mut dependencies = BTreeSet::new();
+    for event in events {
+        match event {
+            Event::Start(Tag::Image(_, url, _)) => {
+                dependencies.extend(local_dependency(base_dir, url));
+            }
+            Event::Html(html) => {
+                for cap in HTML_REF.captures_iter(html) {
+                    dependencies.extend(local_dependency(base_dir, cap.get(1).unwrap().as_str()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    dependencies
+}
+
+// Resolve a URL to a local file, if it refers to one
+fn local_dependency(base_dir: &Path, url: &str) -> Option<PathBuf> {
+    let url = url.trim();
+    if url.is_empty()
+        || url.starts_with('#')
+        || url.starts_with("//")
+        || url.contains("://")
+        || url.starts_with("data:")
+        || url.starts_with("mailto:")
+    {
+        return None;
+    }
+
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let path = percent_decode(path);
+
+    // Root-relative URLs are served from the document's directory
+    Some(base_dir.join(path.trim_start_matches('/')))
+}
+
+// Decode %XX escapes in a URL path
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Collect headings and assign each a unique slug
+fn collect_headings(events: &[Event]) -> Vec<Heading> {
+    let mut headings: Vec<Heading> = Vec::new();
+    let mut current: Option<(HeadingLevel, String)> = None;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Heading(level, _, _)) => current = Some((*level, String::new())),
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, heading_text)) = current.as_mut() {
+                    heading_text.push_str(text);
+                }
+            }
+            Event::End(Tag::Heading(..)) => {
+                if let Some((level, text)) = current.take() {
+                    let base = slugify(&text);
+                    let mut id = base.clone();
+                    let mut suffix = 1;
+                    while PAGE_IDS.contains(&id.as_str())
+                        || headings.iter().any(|heading| heading.id == id)
+                    {
+                        id = format!("{}-{}", base, suffix);
+                        suffix += 1;
+                    }
+                    headings.push(Heading { level, text, id });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    headings
+}
+
+// Turn heading text into an anchor id
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.trim().to_lowercase().chars() {
+        if c.is_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if (c == ' ' || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-').to_string();
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    }
+}
+
+// Render the table of contents for a page template
+fn table_of_contents(headings: &[Heading]) -> Markup {
+    html! {
+        @if !headings.is_empty() {
+            nav class="toc" {
+                ul {
+                    @for heading in headings {
+                        li class=(format!("toc-{}", heading.level)) {
+                            a href=(format!("#{}", heading.id)) { (heading.text) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Strip the raw HTML of an untrusted document down to an allowlist of tags and
+// attributes, keeping the markup pulldown-cmark and marv generate (classes,
+// heading ids, table alignment, task list checkboxes and source line markers).
+// Only headings keep an id, and only the one marv gave them, so the document
+// can't take the id of an element the page's scripts look up.
+fn sanitize_html(html: &str, headings: &[Heading]) -> String {
+    let heading_ids: BTreeSet<String> = headings.iter().map(|h| h.id.clone()).collect();
+    ammonia::Builder::default()
+        .add_generic_attributes(["class", "align"])
+        .add_tag_attributes("h1", ["id"])
+        .add_tag_attributes("h2", ["id"])
+        .add_tag_attributes("h3", ["id"])
+        .add_tag_attributes("h4", ["id"])
+        .add_tag_attributes("h5", ["id"])
+        .add_tag_attributes("h6", ["id"])
+        .attribute_filter(move |_, attribute, value| {
+            if attribute == "id" && !heading_ids.contains(value) {
+                None
+            } else {
+                Some(value.into())
+            }
+        })
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tag_attributes("div", ["data-source-line"])
+        .add_tag_attributes("th", ["style"])
+        .add_tag_attributes("td", ["style"])
+        .clean(html)
+        .to_string()
+}
+
+// Substitute {{placeholder}} markers in a user template in a single pass
+fn apply_template(template: &str, values: &[(&str, String)]) -> String {
+    static PLACEHOLDER: LazyLock<regex::Regex> = LazyLock::new(|| {
+        regex::Regex::new(r"\{\{\s*(\w+)\s*\}\}").expect("valid placeholder pattern")
+    });
+    PLACEHOLDER
+        .replace_all(template, |cap: &regex::Captures| {
+            let name = cap.get(1).unwrap().as_str();
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| cap.get(0).unwrap().as_str().to_string())
+        })
+        .into_owned()
 }
 
 // Process markdown to detect and prepare Mermaid diagrams and syntax highlighting
-pub fn process_mermaid_diagrams(html: &str) -> String {
+pub fn process_mermaid_diagrams(html: &str, syntax_theme: &str) -> String {
     // Set up syntax highlighting
     let ss = SyntaxSet::load_defaults_newlines();
     let ts = ThemeSet::load_defaults();
-    let theme = &ts.themes["base16-ocean.dark"];
+    let theme = ts.themes.get(syntax_theme).unwrap_or_else(|| {
+        warn!("Unknown syntax theme {:?}, using the default", syntax_theme);
+        &ts.themes[DEFAULT_SYNTAX_THEME]
+    });
 
     // First pass: extract code blocks for syntax highlighting
     let mut intermediate = html.to_string();
 
     // A more flexible pattern for code blocks that handles entities better
-    let re_code =
+    static CODE_BLOCK: LazyLock<regex::Regex> = LazyLock::new(|| {
         regex::Regex::new(r#"<pre><code class="language-([^"]+)">([\s\S]*?)</code></pre>"#)
-            .unwrap();
-    for cap in re_code.captures_iter(html) {
+            .expect("valid code block pattern")
+    });
+    for cap in CODE_BLOCK.captures_iter(html) {
         let lang = cap.get(1).unwrap().as_str();
         let code = cap.get(2).unwrap().as_str();
 
@@ -264,3 +1005,159 @@ pub fn process_mermaid_diagrams(html: &str) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Render options without any user customisation
+    fn options() -> RenderOptions {
+        RenderOptions {
+            theme: "dark".to_string(),
+            syntax_theme: DEFAULT_SYNTAX_THEME.to_string(),
+            mermaid_url: "https://cdn.example.com/mermaid.js".to_string(),
+            max_width: "800px".to_string(),
+            stylesheets: Vec::new(),
+            template: None,
+            include_depth: 8,
+            include_root: None,
+            encoding: encoding_rs::WINDOWS_1252,
+            diagram_tools: diagrams::DiagramTools {
+                graphviz_command: "dot".to_string(),
+                plantuml_command: "plantuml".to_string(),
+                timeout: Duration::from_secs(1),
+                cache_dir: None,
+            },
+            sanitize: false,
+            script_nonce: "nonce".to_string(),
+        }
+    }
+
+    #[test]
+    fn fills_template_placeholders_once() {
+        let options = RenderOptions {
+            template: Some(
+                "<html><head>{{ styles }}</head><body>{{title}}|{{content}}|{{unknown}}</body></html>"
+                    .to_string(),
+            ),
+            ..options()
+        };
+        let page = markdown_to_html("Hello {{title}}", "/tmp/doc.md", &options);
+
+        assert!(page.html.starts_with("<html><head><style>"));
+        assert!(page.html.contains("<body>/tmp/doc.md|"));
+        // Placeholders in the document itself are left alone
+        assert!(page.html.contains("Hello {{title}}</p>"));
+        assert!(page.html.contains("|{{unknown}}</body>"));
+    }
+
+    #[test]
+    fn keeps_user_stylesheets_inside_their_style_element() {
+        let options = RenderOptions {
+            stylesheets: vec!["p { color: red } </STYLE><script>alert(1)</script>".to_string()],
+            ..options()
+        };
+        let page = markdown_to_html("text", "/tmp/doc.md", &options);
+
+        assert!(!page.html.to_lowercase().contains("</style><script>alert"));
+        assert!(page
+            .html
+            .contains(r"p { color: red } <\/STYLE><script>alert(1)"));
+    }
+
+    #[test]
+    fn passes_the_theme_to_mermaid_as_a_string() {
+        let options = RenderOptions {
+            theme: "it's </script>".to_string(),
+            ..options()
+        };
+        let page = markdown_to_html("text", "/tmp/doc.md", &options);
+        assert!(page.html.contains(r#"theme: "it's <\/script>""#));
+    }
+
+    #[test]
+    fn marks_blocks_with_their_line_in_the_document_itself() {
+        let dir = utils::file::test_dir("source-lines");
+        std::fs::write(dir.join("part.md"), "## Part\n\nOne\n\nTwo\n").unwrap();
+        let markdown = "# Title\n\n<!-- include: part.md -->\n\n## After\n";
+        let path = dir.join("doc.md");
+        let page = markdown_to_html(markdown, &path.to_string_lossy(), &options());
+
+        let line_before = |text: &str| {
+            let marker = page.html[..page.html.find(text).unwrap()]
+                .rfind("data-source-line=\"")
+                .unwrap();
+            let line = &page.html[marker + 18..];
+            line[..line.find('"').unwrap()].to_string()
+        };
+        assert_eq!(line_before("Title</h1>"), "1");
+        // Included blocks point at the include
+        assert_eq!(line_before("Part</h2>"), "3");
+        assert_eq!(line_before("Two</p>"), "3");
+        assert_eq!(line_before("After</h2>"), "5");
+    }
+
+    #[test]
+    fn renders_a_diagram_file_as_escaped_source() {
+        let source = "graph TD\n  A[\"<b>x</b> & y\"] --> B\n";
+        let page = mermaid_to_html(source, "/tmp/diagrams/flow.mmd", &options());
+
+        assert!(page.html.contains("<title>flow</title>"));
+        let diagram = &page.html[page.html.find("<pre id=\"diagram\"").unwrap()..];
+        let diagram = &diagram[..diagram.find("</pre>").unwrap()];
+        assert!(diagram.contains(r#"class="mermaid" data-name="flow">graph TD"#));
+        assert!(diagram.contains("&lt;b&gt;x&lt;/b&gt; &amp; y"));
+        assert!(!diagram.contains("<b>"));
+
+        // Every script carries the page's nonce
+        let scripts = page.html.matches("<script").count();
+        assert_eq!(
+            scripts,
+            page.html.matches(r#"<script nonce="nonce""#).count()
+        );
+        assert!(scripts >= 3);
+    }
+
+    #[test]
+    fn keeps_only_heading_ids_in_untrusted_html() {
+        let markdown = "# Content\n\n<div id=\"marv-status\">x</div>\n\n\
+                        <p id=\"content\">y</p>\n\n## Notes\n";
+        let sanitized = RenderOptions {
+            sanitize: true,
+            ..options()
+        };
+        let page = markdown_to_html(markdown, "/tmp/doc.md", &sanitized);
+
+        assert!(!page.html.contains(r#"id="marv-status""#), "{}", page.html);
+        assert_eq!(page.html.matches(r#"id="content""#).count(), 1);
+        assert!(page.html.contains(r#"<h1 id="content-1">Content</h1>"#));
+        assert!(page.html.contains(r#"<h2 id="notes">Notes</h2>"#));
+    }
+
+    #[test]
+    fn strips_scripts_and_handlers_from_untrusted_html() {
+        let markdown = "<script>alert(1)</script>\n\n\
+                        <img src=\"cat.png\" onerror=\"alert(2)\">\n\n\
+                        <a href=\"javascript:alert(3)\" onclick=\"alert(4)\">link</a>\n\n\
+                        - [x] done\n";
+        let sanitized = RenderOptions {
+            sanitize: true,
+            ..options()
+        };
+        let page = markdown_to_html(markdown, "/tmp/doc.md", &sanitized);
+
+        assert!(!page.html.contains("alert("), "{}", page.html);
+        assert!(!page.html.contains("onerror"));
+        assert!(page.html.contains(r#"<img src="cat.png">"#));
+        assert!(page.html.contains(">link</a>"));
+        // Task list checkboxes survive
+        assert!(page.html.contains(r#"type="checkbox""#));
+        assert!(page.html.contains(r#" checked="""#));
+
+        // A trusted document keeps its HTML as written
+        let page = markdown_to_html(markdown, "/tmp/doc.md", &options());
+        assert!(page.html.contains("<script>alert(1)</script>"));
+        assert!(page.html.contains(r#"onerror="alert(2)""#));
+    }
+}
@@ -1,277 +1,553 @@
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
-use std::process::Command;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
 use futures::StreamExt;
-use tokio::sync::{mpsc, watch};
+use rand::Rng;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
+use warp::host::Authority;
+use warp::http::{header, HeaderValue, StatusCode};
 use warp::ws::{Message, WebSocket};
-use warp::Filter;
+use warp::{Filter, Reply};
 
+use crate::config::Config;
+use crate::logs;
+use crate::protocol::{ClientMessage, ServerMessage, Severity, PROTOCOL_VERSION};
 use crate::renderer;
+use crate::tls;
 use crate::utils;
-use crate::watcher;
+use crate::watcher::{self, FileState};
+
+// Banner shown while the previewed file is missing
+const FILE_DELETED_MESSAGE: &str = "File deleted. Waiting for it to reappear...";
+
+// How often the parent process is checked
+const PARENT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Files next to a server's record holding its secrets: the control token
+// used by local marv commands, and the access token for shared servers
+const CONTROL_TOKEN: &str = "token";
+const ACCESS_TOKEN: &str = "access";
+
+// Content-Security-Policy for everything but the page itself
+const FILES_POLICY: &str = "default-src 'none'; img-src * data:; style-src * 'unsafe-inline'; \
+                            font-src * data:; media-src * data:; sandbox";
+
+// How long local commands wait for a server's API
+const API_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long a new server is given to answer before starting it is reported done
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long pages are given to disconnect after being told the server is stopping
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+// How long a new server waits for pages left open on its port to reconnect,
+// which they try every two seconds (every ten after the server said goodbye)
+const RECONNECT_WAIT: Duration = Duration::from_millis(2500);
 
 // Start preview server
-pub async fn start_preview_server(input_path: &Path, server_dir: &Path) -> Result<()> {
+pub async fn start_preview_server(
+    input_path: &Path,
+    server_dir: &Path,
+    config: &Config,
+    parent_pid: Option<u32>,
+) -> Result<()> {
     let server_info_path = utils::file::get_server_info_path(input_path, server_dir);
     info!("Server info path: {:?}", server_info_path);
 
     // Check if server is already running
     if server_info_path.exists() {
         if let Ok((port, pid)) = utils::file::read_server_info(&server_info_path) {
-            if utils::process::is_process_running(pid) {
+            let endpoint = Endpoint::from_record(&server_info_path, port);
+            let control_token =
+                utils::file::read_server_secret(&server_info_path, CONTROL_TOKEN).ok();
+            // A server that is still starting gets the time a new one would
+            let running = server_is_healthy(
+                endpoint,
+                &utils::file::get_file_hash(input_path),
+                control_token.as_deref(),
+            )
+            .await
+                || (utils::process::is_process_running(pid)
+                    && wait_until_ready(&server_info_path, endpoint, input_path).await);
+            if running {
+                let access_token =
+                    utils::file::read_server_secret(&server_info_path, ACCESS_TOKEN).ok();
                 println!(
                     "A preview server is already running for this file on port {}.",
                     port
                 );
-                println!("Preview available at http://localhost:{}", port);
+                endpoint.print_urls(access_token.as_deref());
+
+                // Bring an open preview to the front rather than opening another
+                if config.open_browser
+                    && request_focus(endpoint, control_token.as_deref()).await == 0
+                {
+                    let url = endpoint.preview_url(access_token.as_deref());
+                    utils::process::open_browser(&url, config.browser.as_deref());
+                }
                 println!(
-                    "Use 'marv --stop {:?}' to stop the server first if you want to restart it.",
+                    "Use 'marv stop {:?}' to stop the server first if you want to restart it.",
                     input_path
                 );
                 return Ok(());
+            } else if utils::process::is_process_running(pid) {
+                // Starting another server could leave two serving the file
+                return Err(anyhow!(
+                    "The preview server for {:?} (pid {}) is not answering on port {}. \
+                     See 'marv logs {}', or run 'marv stop {}' first.",
+                    input_path,
+                    pid,
+                    port,
+                    input_path.display(),
+                    input_path.display()
+                ));
             } else {
-                // Process is not running but file exists, remove stale file
-                std::fs::remove_file(&server_info_path)
-                    .context("Failed to remove stale server info file")?;
-
-                // Also remove file path reference if it exists
-                let filepath_info = server_info_path.with_extension("filepath");
-                if filepath_info.exists() {
-                    std::fs::remove_file(&filepath_info)
-                        .context("Failed to remove stale filepath info file")?;
-                }
+                // The server is gone, so the record is stale
+                debug!("Removing stale record of server {} on port {}", pid, port);
+                utils::file::remove_server_info(&server_info_path)?;
             }
         }
     }
 
+    // Create the server info directory if it doesn't exist
+    utils::file::create_private_dir(server_dir)?;
+
     // Find an available port
-    let port = find_available_port().await?;
+    let last_port = utils::file::read_last_port(config, input_path);
+    let port = choose_port(config, input_path, true).await?;
 
     println!(
         "Starting preview server for {:?} on port {}",
         input_path, port
     );
-    println!("Preview will be available at http://localhost:{}", port);
-
-    // Create the server info directory if it doesn't exist
-    if let Some(parent) = server_info_path.parent() {
-        if !parent.exists() {
-            std::fs::create_dir_all(parent)
-                .context(format!("Failed to create directory: {:?}", parent))?;
-            info!("Created server info directory: {:?}", parent);
-        }
-    }
+    let endpoint = Endpoint {
+        bind: config.bind,
+        port,
+        tls: config.tls,
+    };
+    let access_token = access_token(&server_info_path, config.bind)?;
+    let url = endpoint.preview_url(access_token.as_deref());
+    println!("Preview will be available at {}", url);
 
     // Launch the server in a background process
     let server_executable = std::env::current_exe()?;
+    let parent_args: Vec<String> = match parent_pid {
+        Some(pid) => vec!["--parent-pid".to_string(), pid.to_string()],
+        None => Vec::new(),
+    };
+
+    // Anything the server writes to stderr before or besides its logs, such
+    // as a panic, goes to its log file
+    let log_path = logs::log_path(config, input_path);
+    if let Some(dir) = log_path.parent() {
+        utils::file::create_private_subdir(dir)?;
+    }
+    let log_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .context(format!("Failed to open log file {:?}", log_path))?;
+
+    // Start the server as a detached process of its own, so that it keeps
+    // running after the terminal closes
+    let mut command = Command::new(server_executable);
+    command
+        .arg("--bg-server")
+        .arg(input_path)
+        .args(&parent_args)
+        .envs(server_env(config))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log_file);
 
-    // Start the server in a background process with nohup or similar
     #[cfg(target_family = "unix")]
     {
-        // Use nohup to keep the process running after terminal closes
-        let input_path_str = input_path.to_str().unwrap_or_default();
-
-        // Start a detached process that runs our server
-        use std::process::Stdio;
-        let process = Command::new("bash")
-            .args([
-                "-c",
-                &format!(
-                    "nohup {} --bg-server \"{}\" > /dev/null 2>&1 & echo $!",
-                    server_executable.display(),
-                    input_path_str
-                ),
-            ])
-            .stdout(Stdio::piped())
-            .spawn()
-            .context("Failed to start background server process")?;
-
-        // Get the PID of the nohup process
-        let output = process
-            .wait_with_output()
-            .context("Failed to get output from background process")?;
-
-        let pid_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        let pid = pid_str
-            .parse::<u32>()
-            .context(format!("Failed to parse PID from output: {}", pid_str))?;
-
-        // Record server information with the background process PID
-        info!("Started background server process with PID: {}", pid);
-        utils::file::write_server_info(&server_info_path, port, pid)?;
+        use std::os::unix::process::CommandExt;
+
+        // SAFETY: setsid is async-signal-safe, so it can run between fork
+        // and exec
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
     }
 
     #[cfg(target_os = "windows")]
     {
-        // Windows version using start command
-        let input_path_str = input_path.to_str().unwrap_or_default();
-
-        // Get the CMD window hidden by using /B flag
-        let process = Command::new("cmd")
-            .args([
-                "/C",
-                "start",
-                "/B",
-                server_executable.to_str().unwrap_or_default(),
-                "--bg-server",
-                input_path_str,
-            ])
-            .spawn()
-            .context("Failed to start background server process")?;
-
-        // Record server information with the current process PID
-        // We'll need to manually find and kill this process on Windows
-        let pid = process.id();
-        info!("Started background server process with PID: {}", pid);
-        utils::file::write_server_info(&server_info_path, port, pid)?;
+        use std::os::windows::process::CommandExt;
+
+        // Without a console window, and away from Ctrl+C in this one
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
     }
 
+    let process = command
+        .spawn()
+        .context("Failed to start background server process")?;
+    let pid = process.id();
+    info!("Started background server process with PID: {}", pid);
+    utils::file::write_server_info(&server_info_path, port, pid, config.bind, config.tls)?;
+
     // Also save the file path reference
     utils::file::save_file_path_in_server_info(&server_info_path, input_path)?;
 
-    // Open browser after a short delay to ensure server is ready
-    let port_clone = port;
-    std::thread::spawn(move || {
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        utils::process::open_browser(&format!("http://localhost:{}", port_clone));
-    });
+    // Wait for the server to answer, so that it can be used (and its port
+    // is taken) once this returns, then open the browser
+    if !wait_until_ready(&server_info_path, endpoint, input_path).await {
+        warn!(
+            "The server did not answer in time (see 'marv logs {}')",
+            input_path.display()
+        );
+    }
+    if config.open_browser {
+        // Pages left open on the last run's port reconnect on their own, so
+        // only open another when none does
+        let reconnected = if last_port == Some(port) {
+            let control_token =
+                utils::file::read_server_secret(&server_info_path, CONTROL_TOKEN).ok();
+            wait_for_pages(endpoint, control_token.as_deref(), RECONNECT_WAIT).await
+        } else {
+            0
+        };
+        if reconnected == 0 {
+            utils::process::open_browser(&url, config.browser.as_deref());
+        }
+    }
 
     println!("Marv server started in the background on port {}", port);
-    println!("Preview available at http://localhost:{}", port);
+    endpoint.print_urls(access_token.as_deref());
     println!(
-        "Use 'marv --stop {:?}' to stop the server when done",
+        "Use 'marv stop {:?}' to stop the server when done",
         input_path
     );
 
     Ok(())
 }
 
-// Run the server (used by start_preview_server)
-pub async fn run_server(input_path: &Path, server_dir: &Path) -> Result<()> {
+// Settings given on the command line, passed on to a background server so
+// that --poll, --bind, --tls and --trust apply to it
+fn server_env(config: &Config) -> [(&'static str, String); 4] {
+    [
+        ("MARV_WATCHER", config.watcher.as_str().to_string()),
+        ("MARV_BIND", config.bind.to_string()),
+        ("MARV_TLS", config.tls.to_string()),
+        ("MARV_TRUST", config.trust.to_string()),
+    ]
+}
+
+// Run the server (used by start_preview_server) until it has been idle for
+// too long or the parent process exits
+pub async fn run_server(
+    input_path: &Path,
+    server_dir: &Path,
+    config: &Config,
+    parent_pid: Option<u32>,
+) -> Result<()> {
     // Get server info
     let server_info_path = utils::file::get_server_info_path(input_path, server_dir);
     let (port, _) = utils::file::read_server_info(&server_info_path)?;
+    serve_document(
+        input_path,
+        config,
+        port,
+        Some(&server_info_path),
+        parent_pid,
+    )
+    .await
+}
+
+// Run a preview server in the foreground until it is stopped, on the given
+// port or the first free one. Nothing is kept in the state or cache
+// directories, so the server is independent of other marv commands and works
+// on a read-only filesystem, and it doesn't stop when idle.
+pub async fn serve(input_path: &Path, config: &Config, port: Option<u16>) -> Result<()> {
+    let port = match port {
+        Some(port) => port,
+        None => choose_port(config, input_path, false).await?,
+    };
+    let config = Config {
+        idle_timeout_secs: 0,
+        cache_diagrams: false,
+        ..config.clone()
+    };
+    serve_document(input_path, &config, port, None, None).await
+}
+
+// Serve a document until the server is stopped. A server started by
+// 'marv start' has a record, next to which its tokens are kept for other
+// marv commands.
+async fn serve_document(
+    input_path: &Path,
+    config: &Config,
+    port: u16,
+    server_info_path: Option<&Path>,
+    parent_pid: Option<u32>,
+) -> Result<()> {
+    let endpoint = Endpoint {
+        bind: config.bind,
+        port,
+        tls: config.tls,
+    };
+    let access_token = match server_info_path {
+        Some(server_info_path) => access_token(server_info_path, config.bind)?,
+        None => (!config.bind.is_loopback()).then(generate_token),
+    };
+
+    // Certificate for HTTPS, checked before anything else starts
+    let identity = if config.tls {
+        let mut addresses = vec![config.bind];
+        addresses.extend(lan_address());
+        Some(match server_info_path {
+            Some(_) => tls::server_identity(config, &addresses)?,
+            None => tls::temporary_identity(config, &addresses)?,
+        })
+    } else {
+        None
+    };
 
     // Set up file watching
-    let (watch_tx, watch_rx) = watch::channel(String::new());
+    let (watch_tx, watch_rx) = watch::channel(FileState::default());
     let watch_tx = Arc::new(Mutex::new(watch_tx));
 
-    // Initial read of the file
-    let content = utils::file::read_file(input_path)?;
+    // Initial read and render of the file
+    let state = watcher::render_file(input_path, config)?;
     {
         let watch_tx_clone = watch_tx.lock().unwrap();
-        watch_tx_clone.send(content)?;
+        watch_tx_clone.send(state)?;
     } // lock is dropped at the end of this scope
 
     // Start file watcher
     let input_path_clone = input_path.to_path_buf();
     let watch_tx_for_watcher = watch_tx.clone();
+    let config_for_watcher = config.clone();
     tokio::spawn(async move {
-        if let Err(e) = watcher::watch_file(input_path_clone, watch_tx_for_watcher).await {
-            eprintln!("Error watching file: {}", e);
+        if let Err(e) =
+            watcher::watch_file(input_path_clone, watch_tx_for_watcher, &config_for_watcher).await
+        {
+            error!("Error watching file: {}", e);
         }
     });
 
-    // Open browser after a short delay to ensure server is ready
-    let port_clone = port;
-    tokio::spawn(async move {
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        utils::process::open_browser(&format!("http://localhost:{}", port_clone));
-    });
-
     // Start web server for preview
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-
-    // Create the warp filter that will pass the content receiver to our handlers
-    let content_filter = warp::any().map(move || watch_rx.clone());
+    let addr = SocketAddr::new(config.bind, port);
 
     // WebSocket route
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .and(content_filter.clone())
-        .map(|ws: warp::ws::Ws, content| {
-            ws.on_upgrade(move |websocket| handle_ws_connection(websocket, content))
-        });
-
-    // Use full file path for the title
-    let filepath = input_path.to_string_lossy().to_string();
-
-    // Main HTML route with auto-refresh script
-    let html_route =
-        warp::path::end()
-            .and(content_filter)
-            .map(move |content: watch::Receiver<String>| {
-                let current_content = content.borrow().clone();
-                let mut html_content = renderer::markdown_to_html(&current_content, &filepath);
-
-                // Insert live reload JavaScript before the closing body tag
-                let script = r#"
-<script>
-    // Connect to WebSocket server
-    const socket = new WebSocket(`ws://${window.location.host}/ws`);
-    
-    // Handle messages from the server
-    socket.onmessage = function(event) {
-        if (event.data === 'refresh') {
-            console.log('Refreshing page due to file change');
-            window.location.reload();
-        }
-    };
-    
-    // Handle connection open
-    socket.onopen = function() {
-        console.log('WebSocket connected for live reload');
-    };
-    
-    // Handle connection close
-    socket.onclose = function() {
-        console.log('WebSocket connection closed');
-        // Try to reconnect after a delay
-        setTimeout(() => {
-            window.location.reload();
-        }, 5000);
-    };
-    
-    // Handle connection errors
-    socket.onerror = function(error) {
-        console.error('WebSocket error:', error);
+    let clients = Clients::new(&utils::file::get_file_hash(input_path));
+    let connected = clients.connected.subscribe();
+    let ws_route = ws_route(watch_rx.clone(), clients.clone());
+
+    // Local files referenced by the document (images and the like)
+    let document_dir = input_path.parent().unwrap_or(Path::new("."));
+    let files_route = files_route(document_dir, watch_rx.clone());
+
+    // Control API, with a token written next to the server record so that
+    // local marv commands can stop the server
+    let token = generate_token();
+    if let Some(server_info_path) = server_info_path {
+        utils::file::write_server_secret(server_info_path, CONTROL_TOKEN, &token)?;
+    }
+    let access = Access {
+        token: access_token.clone(),
+        control_token: token.clone(),
+        cookie: format!("marv_token_{}", port),
+        secure: config.tls,
     };
-</script>
-</body>
-"#;
-
-                // Replace the closing body tag with our script + closing body tag
-                html_content = html_content.replace("</body>", script);
+    let stop = Arc::new(Notify::new());
+    let api_route = api_routes(
+        Api {
+            path: input_path.to_path_buf(),
+            port,
+            started: Instant::now(),
+            token,
+            clients: clients.clone(),
+            stop: stop.clone(),
+        },
+        watch_rx.clone(),
+    );
 
-                warp::reply::html(html_content)
-            });
+    // Main HTML route with auto-refresh script. Pages of untrusted documents
+    // only run their own scripts.
+    let html_route = page_route(
+        watch_rx.clone(),
+        clients.clone(),
+        (!config.trust).then(|| config.mermaid_url.clone()),
+    );
 
-    // Combine routes
-    let routes = html_route.or(ws_route);
+    // Combine routes, all of which need the access token on a shared server
+    let cookie_access = access.clone();
+    let trusted = config.trust;
+    let routes = known_host(config.bind, &config.allowed_hosts)
+        .and(access_filter(access))
+        .and(html_route.or(ws_route).or(api_route).or(files_route))
+        .map(move |reply| cookie_access.with_cookie(reply))
+        .map(move |response| with_security_headers(response, trusted))
+        .recover(handle_rejection);
 
     // Print user information
     println!("Marv server running for {:?}", input_path);
-    println!("Preview available at http://localhost:{}", port);
-    println!(
-        "Press Ctrl+C to stop, or use 'marv --stop {:?}' from another terminal",
-        input_path
-    );
+    endpoint.print_urls(access_token.as_deref());
+    if server_info_path.is_some() {
+        println!(
+            "Press Ctrl+C to stop, or use 'marv stop {:?}' from another terminal",
+            input_path
+        );
+    } else {
+        println!("Press Ctrl+C to stop");
+    }
+
+    // Stop on SIGTERM or Ctrl+C, when nobody is looking at the preview, or
+    // when the editor has gone, telling any open pages why
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let shutdown_clients = clients.clone();
+    let shutdown = async move {
+        let reason = tokio::select! {
+            signal = wait_for_signal() => {
+                info!("Received {}, shutting down", signal);
+                "the preview server was stopped"
+            }
+            _ = stop.notified() => "the preview server was stopped",
+            _ = wait_until_idle(connected, idle_timeout) => {
+                info!("No clients connected for {:?}, shutting down", idle_timeout);
+                "no page was connected"
+            }
+            _ = wait_for_exit(parent_pid) => {
+                info!("Parent process exited, shutting down");
+                "the editor exited"
+            }
+        };
+        shutdown_clients.say_goodbye(reason);
+    };
 
     // Run the web server (this blocks until the server stops)
     info!("Starting web server on port {}", port);
-    warp::serve(routes).run(addr).await;
+    match identity {
+        Some(identity) => {
+            let (_, server) = warp::serve(routes)
+                .tls()
+                .cert(&identity.cert)
+                .key(&identity.key)
+                .try_bind_with_graceful_shutdown(addr, shutdown)
+                .context(format!("Failed to serve HTTPS on {}", addr))?;
+            server.await;
+        }
+        None => {
+            let (_, server) = warp::serve(routes)
+                .try_bind_with_graceful_shutdown(addr, shutdown)
+                .context(format!("Failed to bind to {}", addr))?;
+            server.await;
+        }
+    }
+
+    // Give pages a moment to receive the goodbye before the process exits
+    wait_until_disconnected(clients.connected.subscribe(), SHUTDOWN_GRACE_PERIOD).await;
+
+    // Remove our own record, unless another server has taken its place or
+    // the stopper already removed it
+    if let Some(server_info_path) = server_info_path {
+        if let Ok((_, pid)) = utils::file::read_server_info(server_info_path) {
+            if pid == std::process::id() {
+                if let Err(e) = utils::file::remove_server_info(server_info_path) {
+                    debug!("Failed to remove server info: {}", e);
+                }
+            }
+        }
+    }
 
+    info!("Server stopped");
     Ok(())
 }
 
+// Resolve on Ctrl+C, or on SIGTERM on unix, naming the signal
+async fn wait_for_signal() -> &'static str {
+    #[cfg(target_family = "unix")]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
+
+// Resolve once every client has disconnected, or after the timeout
+async fn wait_until_disconnected(mut connected: watch::Receiver<usize>, timeout: Duration) {
+    let _ = tokio::time::timeout(timeout, connected.wait_for(|count| *count == 0)).await;
+}
+
+// Resolve once no client has been connected for the timeout (never if zero)
+async fn wait_until_idle(mut connected: watch::Receiver<usize>, timeout: Duration) {
+    if timeout.is_zero() {
+        return futures::future::pending().await;
+    }
+
+    loop {
+        while *connected.borrow_and_update() > 0 {
+            if connected.changed().await.is_err() {
+                return futures::future::pending().await;
+            }
+        }
+        if tokio::time::timeout(timeout, connected.changed())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// Resolve once the given process has exited (never without one)
+async fn wait_for_exit(pid: Option<u32>) {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return futures::future::pending().await,
+    };
+
+    info!("Watching parent process {}", pid);
+    loop {
+        tokio::time::sleep(PARENT_POLL_INTERVAL).await;
+        if !tokio::task::spawn_blocking(move || utils::process::is_process_running(pid))
+            .await
+            .unwrap_or(true)
+        {
+            return;
+        }
+    }
+}
+
 // Stop preview server
-pub fn stop_preview_server(input_path: &Path, server_dir: &Path) -> Result<()> {
+pub async fn stop_preview_server(input_path: &Path, server_dir: &Path) -> Result<()> {
     let server_info_path = utils::file::get_server_info_path(input_path, server_dir);
 
     // Check if server info file exists
@@ -283,8 +559,7 @@ pub fn stop_preview_server(input_path: &Path, server_dir: &Path) -> Result<()> {
     let (port, pid) = utils::file::read_server_info(&server_info_path)?;
 
     // Try to terminate the process
-    if utils::process::is_process_running(pid) {
-        utils::process::kill_process(pid)?;
+    if stop_server_process(&server_info_path, port, pid).await? {
         println!(
             "Stopped preview server for {:?} (port: {}, pid: {})",
             input_path, port, pid
@@ -296,38 +571,69 @@ pub fn stop_preview_server(input_path: &Path, server_dir: &Path) -> Result<()> {
         );
     }
 
-    // Remove server info file
-    std::fs::remove_file(&server_info_path).context("Failed to remove server info file")?;
-
-    // Also remove file path reference if it exists
-    let filepath_info = server_info_path.with_extension("filepath");
-    if filepath_info.exists() {
-        std::fs::remove_file(&filepath_info).context("Failed to remove filepath info file")?;
-    }
+    // A server removes its own record as it exits, so only what it left
+    // behind (e.g. after a crash) needs removing here
+    utils::file::remove_server_info(&server_info_path)?;
 
     Ok(())
 }
 
-// Find an available port in the 4000-4999 range
-async fn find_available_port() -> Result<u16> {
-    for port in 4000..5000 {
+// Choose the port to serve a document on. The port it had last time (when
+// remembered) or one derived from its path is preferred, so that its preview
+// URL stays the same across restarts and open tabs reconnect; the rest of the
+// configured range is only searched when that port is taken.
+async fn choose_port(config: &Config, input_path: &Path, remember: bool) -> Result<u16> {
+    let range = config.port_min..=config.port_max;
+    let preferred = preferred_port(config, input_path);
+    let last = remember
+        .then(|| utils::file::read_last_port(config, input_path))
+        .flatten()
+        .filter(|port| range.contains(port));
+
+    let candidates = last
+        .into_iter()
+        .chain(range.clone().skip((preferred - config.port_min) as usize))
+        .chain(range.clone().take((preferred - config.port_min) as usize));
+    for port in candidates {
         debug!("Trying port {}", port);
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let addr = SocketAddr::new(config.bind, port);
         if tokio::net::TcpListener::bind(addr).await.is_ok() {
+            if Some(port) != last && port != preferred {
+                info!(
+                    "Port {} is taken, using {}",
+                    last.unwrap_or(preferred),
+                    port
+                );
+            }
             info!("Found available port: {}", port);
+            if remember {
+                if let Err(e) = utils::file::write_last_port(config, input_path, port) {
+                    debug!("Failed to remember port {}: {:#}", port, e);
+                }
+            }
             return Ok(port);
         }
     }
-    Err(anyhow!("No available ports in the range 4000-4999"))
+    Err(anyhow!(
+        "No available ports in the range {}-{}",
+        config.port_min,
+        config.port_max
+    ))
+}
+
+// A port in the configured range derived from a document's path, the same on
+// every run
+fn preferred_port(config: &Config, input_path: &Path) -> u16 {
+    let hash = utils::file::get_file_hash(input_path);
+    let value = u32::from_str_radix(&hash[..8], 16).unwrap_or(0);
+    let size = u32::from(config.port_max - config.port_min) + 1;
+    config.port_min + (value % size) as u16
 }
 
 // Kill all running marv servers
-pub fn kill_all_servers() -> Result<()> {
+pub async fn kill_all_servers(server_dir: &Path) -> Result<()> {
     info!("Attempting to kill all marv servers...");
 
-    // Get server info directory
-    let server_dir = utils::file::get_server_info_dir()?;
-
     // Ensure directory exists
     if !server_dir.exists() {
         println!("No marv servers are running.");
@@ -351,29 +657,20 @@ pub fn kill_all_servers() -> Result<()> {
                 // Try to read the server info
                 if let Ok((port, pid)) = utils::file::read_server_info(&path) {
                     // Try to terminate the process
-                    if utils::process::is_process_running(pid) {
-                        if let Err(e) = utils::process::kill_process(pid) {
-                            eprintln!("Error terminating process (pid: {}): {}", pid, e);
-                        } else {
+                    match stop_server_process(&path, port, pid).await {
+                        Ok(true) => {
                             println!("Terminated server on port {} (pid: {})", port, pid);
                             terminated_count += 1;
                         }
+                        Ok(false) => (),
+                        Err(e) => {
+                            eprintln!("Error terminating process (pid: {}): {}", pid, e)
+                        }
                     }
 
-                    // Remove server info file regardless of whether process was running
-                    if let Err(e) = fs::remove_file(&path) {
-                        eprintln!("Error removing server info file {:?}: {}", path, e);
-                    }
-
-                    // Also remove file path reference if it exists
-                    let filepath_info = path.with_extension("filepath");
-                    if filepath_info.exists() {
-                        if let Err(e) = fs::remove_file(&filepath_info) {
-                            eprintln!(
-                                "Error removing filepath info file {:?}: {}",
-                                filepath_info, e
-                            );
-                        }
+                    // Remove server info regardless of whether process was running
+                    if let Err(e) = utils::file::remove_server_info(&path) {
+                        eprintln!("Error removing server info {:?}: {}", path, e);
                     }
                 }
             }
@@ -392,49 +689,2059 @@ pub fn kill_all_servers() -> Result<()> {
     Ok(())
 }
 
-// Handle websocket connections
-async fn handle_ws_connection(ws: WebSocket, mut file_updates: watch::Receiver<String>) {
-    // Split the websocket into sender and receiver
-    let (ws_tx, mut ws_rx) = ws.split();
+// Print whether a preview server is running for a document, and where to
+// find it. Returns whether one is.
+pub async fn print_status(input_path: &Path, server_dir: &Path) -> Result<bool> {
+    let server_info_path = utils::file::get_server_info_path(input_path, server_dir);
+    let info = match utils::file::read_server_info(&server_info_path) {
+        Ok((port, pid)) => server_details(&server_info_path, port)
+            .await
+            .map(|info| (port, pid, info)),
+        Err(_) => None,
+    };
 
-    // Create a channel for messages to send to the websocket
-    let (tx, rx) = mpsc::unbounded_channel();
-    let rx = UnboundedReceiverStream::new(rx);
+    let (port, pid, info) = match info {
+        Some(info) if info.2["doc_id"] == utils::file::get_file_hash(input_path) => info,
+        _ => {
+            println!("No preview server is running for {:?}", input_path);
+            return Ok(false);
+        }
+    };
 
-    // Forward messages from the channel to the websocket
-    tokio::task::spawn(rx.forward(ws_tx));
+    println!(
+        "Preview server running for {:?} on port {} (pid: {})",
+        input_path, port, pid
+    );
+    let access_token = utils::file::read_server_secret(&server_info_path, ACCESS_TOKEN).ok();
+    Endpoint::from_record(&server_info_path, port).print_urls(access_token.as_deref());
+    println!(
+        "{} page(s) connected, up for {}s",
+        info["clients"], info["uptime_secs"]
+    );
+    Ok(true)
+}
 
-    // Clone the sender for our file watcher
-    let tx_clone = tx.clone();
+// List the servers recorded in the state directory
+pub async fn list_servers(server_dir: &Path) -> Result<()> {
+    let mut servers = Vec::new();
+    if let Ok(entries) = fs::read_dir(server_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "server") {
+                if let Ok((port, pid)) = utils::file::read_server_info(&path) {
+                    servers.push((port, pid, path));
+                }
+            }
+        }
+    }
 
-    // Spawn a task to watch for file changes
-    tokio::task::spawn(async move {
-        // Set up a changed watcher
-        let mut last_seen = file_updates.borrow_and_update().clone();
+    if servers.is_empty() {
+        println!("No marv servers are running.");
+        return Ok(());
+    }
 
-        while file_updates.changed().await.is_ok() {
-            let current = file_updates.borrow().clone();
-            if current != last_seen {
-                debug!("File content changed, sending refresh notification");
-                if tx_clone.send(Ok(Message::text("refresh"))).is_err() {
-                    // Client disconnected
+    servers.sort();
+    println!("{:<6} {:<8} {:<14} FILE", "PORT", "PID", "STATUS");
+    for (port, pid, path) in servers {
+        let status = match server_details(&path, port).await {
+            Some(info) => format!("{} page(s)", info["clients"]),
+            None => "not responding".to_string(),
+        };
+        let file = utils::file::read_file_path_in_server_info(&path)
+            .map(|file| file.display().to_string())
+            .unwrap_or_else(|_| "?".to_string());
+        println!("{:<6} {:<8} {:<14} {}", port, pid, status, file);
+    }
+
+    Ok(())
+}
+
+// What a recorded server reports about itself through /api/info
+async fn server_details(server_info_path: &Path, port: u16) -> Option<serde_json::Value> {
+    let endpoint = Endpoint::from_record(server_info_path, port);
+    let token = utils::file::read_server_secret(server_info_path, CONTROL_TOKEN).ok();
+    match api_request(endpoint, "GET", "/api/info", token.as_deref()).await {
+        Ok((200, body)) => serde_json::from_str(&body).ok(),
+        _ => None,
+    }
+}
+
+// State behind the /api routes
+#[derive(Clone)]
+struct Api {
+    // The previewed document
+    path: PathBuf,
+    port: u16,
+    started: Instant,
+    // Secret required to stop the server, shared with local marv commands
+    // through the state directory
+    token: String,
+    clients: Clients,
+    // Signalled when a stop is requested
+    stop: Arc<Notify>,
+}
+
+// JSON control API:
+//   GET  /api/health  liveness, with the document id so a reused port or PID
+//                     can't be mistaken for this server
+//   GET  /api/info    served path, version, uptime and connected clients
+//   GET  /api/render  the current rendered HTML, without the live reload script
+//   POST /api/focus   ask connected pages to come to the front, returning how
+//                     many there are
+//   POST /api/stop    stop the server
+// POST requests require "Authorization: Bearer <token>".
+fn api_routes(
+    api: Api,
+    file_updates: watch::Receiver<FileState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let with_api = warp::any().map(move || api.clone());
+    let with_state = warp::any().map(move || file_updates.clone());
+
+    let health = warp::path!("api" / "health")
+        .and(warp::get())
+        .and(with_api.clone())
+        .map(|api: Api| {
+            warp::reply::json(&serde_json::json!({
+                "status": "ok",
+                "doc_id": api.clients.doc_id,
+                "pid": std::process::id(),
+            }))
+        });
+
+    let info = warp::path!("api" / "info")
+        .and(warp::get())
+        .and(with_api.clone())
+        .and(with_state.clone())
+        .map(|api: Api, state: watch::Receiver<FileState>| {
+            let state = state.borrow();
+            warp::reply::json(&serde_json::json!({
+                "path": api.path,
+                "doc_id": api.clients.doc_id,
+                "version": env!("CARGO_PKG_VERSION"),
+                "protocol": PROTOCOL_VERSION,
+                "port": api.port,
+                "pid": std::process::id(),
+                "uptime_secs": api.started.elapsed().as_secs(),
+                "clients": *api.clients.connected.borrow(),
+                "render_id": api.clients.render_id(state.revision),
+                "deleted": state.deleted,
+                "error": state.error.as_ref().map(|error| &error.message),
+            }))
+        });
+
+    let render = warp::path!("api" / "render")
+        .and(warp::get())
+        .and(with_state)
+        .map(|state: watch::Receiver<FileState>| warp::reply::html(state.borrow().html.clone()));
+
+    let focus = warp::path!("api" / "focus")
+        .and(warp::post())
+        .and(with_api.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|api: Api, authorization: Option<String>| {
+            if !api.is_authorized(authorization.as_deref()) {
+                return unauthorized();
+            }
+
+            let clients = *api.clients.connected.borrow();
+            if clients > 0 {
+                info!("Asking {} page(s) to come to the front", clients);
+                api.clients.broadcast(ServerMessage::Focus);
+            }
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "clients": clients })),
+                StatusCode::OK,
+            )
+        });
+
+    let stop = warp::path!("api" / "stop")
+        .and(warp::post())
+        .and(with_api)
+        .and(warp::header::optional::<String>("authorization"))
+        .map(|api: Api, authorization: Option<String>| {
+            if !api.is_authorized(authorization.as_deref()) {
+                return unauthorized();
+            }
+
+            info!("Stop requested through the API");
+            api.stop.notify_one();
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "status": "stopping" })),
+                StatusCode::OK,
+            )
+        });
+
+    health.or(info).or(render).or(focus).or(stop)
+}
+
+impl Api {
+    // Whether a request carries the control token
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        bearer_matches(authorization, &self.token)
+    }
+}
+
+// Response to an API request without the control token
+fn unauthorized() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": "invalid token" })),
+        StatusCode::UNAUTHORIZED,
+    )
+}
+
+// Generate a random secret
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+// Make a request to a local server's API, returning the status code and body
+async fn api_request(
+    endpoint: Endpoint,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+) -> Result<(u16, String)> {
+    let addr = endpoint.local_address();
+    tokio::time::timeout(API_TIMEOUT, async {
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, addr.to_string());
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(hyper::Body::empty())?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let response = if endpoint.tls {
+            send_request(tls::connect(stream, addr).await?, request).await?
+        } else {
+            send_request(stream, request).await?
+        };
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    })
+    .await
+    .map_err(|_| anyhow!("{} did not answer in time", addr))?
+}
+
+// Send a request over a new HTTP/1 connection
+async fn send_request(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>> {
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("API connection failed: {}", e);
+        }
+    });
+    Ok(sender.send_request(request).await?)
+}
+
+// Check that a server is alive and previewing the given document. The
+// control token is needed to reach a shared server.
+async fn server_is_healthy(endpoint: Endpoint, doc_id: &str, token: Option<&str>) -> bool {
+    let addr = endpoint.local_address();
+    match api_request(endpoint, "GET", "/api/health", token).await {
+        Ok((200, body)) => serde_json::from_str::<serde_json::Value>(&body)
+            .map(|health| health["doc_id"] == doc_id)
+            .unwrap_or(false),
+        Ok((status, _)) => {
+            debug!("Health check on {} returned {}", addr, status);
+            false
+        }
+        Err(e) => {
+            debug!("Health check on {} failed: {}", addr, e);
+            false
+        }
+    }
+}
+
+// Ask a server to stop through its API
+async fn request_stop(endpoint: Endpoint, token: &str) -> Result<()> {
+    match api_request(endpoint, "POST", "/api/stop", Some(token)).await? {
+        (200, _) => Ok(()),
+        (status, body) => Err(anyhow!(
+            "Server refused to stop ({}): {}",
+            status,
+            body.trim()
+        )),
+    }
+}
+
+// Ask a server's pages to come to the front, returning how many there are
+async fn request_focus(endpoint: Endpoint, token: Option<&str>) -> u64 {
+    match api_request(endpoint, "POST", "/api/focus", token).await {
+        Ok((200, body)) => serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|focus| focus["clients"].as_u64())
+            .unwrap_or(0),
+        Ok((status, _)) => {
+            debug!("Focus request returned {}", status);
+            0
+        }
+        Err(e) => {
+            debug!("Focus request failed: {}", e);
+            0
+        }
+    }
+}
+
+// Wait for pages to connect to a server, bringing them to the front. Returns
+// how many there are, or 0 if none connects in time.
+async fn wait_for_pages(endpoint: Endpoint, token: Option<&str>, wait: Duration) -> u64 {
+    let deadline = Instant::now() + wait;
+    loop {
+        let clients = request_focus(endpoint, token).await;
+        if clients > 0 || Instant::now() >= deadline {
+            return clients;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+// Wait for a newly started server to write its control token and answer
+// health checks. Returns whether it did in time.
+async fn wait_until_ready(server_info_path: &Path, endpoint: Endpoint, input_path: &Path) -> bool {
+    let doc_id = utils::file::get_file_hash(input_path);
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(token) = utils::file::read_server_secret(server_info_path, CONTROL_TOKEN) {
+            if server_is_healthy(endpoint, &doc_id, Some(&token)).await {
+                return true;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+// Stop a server, through its API if possible and by signal otherwise.
+// Returns whether the process was running.
+async fn stop_server_process(server_info_path: &Path, port: u16, pid: u32) -> Result<bool> {
+    if !utils::process::is_process_running(pid) {
+        return Ok(false);
+    }
+
+    let endpoint = Endpoint::from_record(server_info_path, port);
+    let token = utils::file::read_server_secret(server_info_path, CONTROL_TOKEN);
+    let stopped = match &token {
+        Ok(token) => request_stop(endpoint, token).await,
+        Err(e) => Err(anyhow!("{:#}", e)),
+    };
+    match stopped {
+        Ok(()) => {
+            // Wait for the server to finish shutting down. Once it stops
+            // answering, the PID may already belong to another process.
+            let doc_id = server_info_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let deadline = Instant::now() + API_TIMEOUT;
+            loop {
+                if !utils::process::is_process_running(pid)
+                    || !server_is_healthy(endpoint, &doc_id, token.as_deref().ok()).await
+                {
+                    return Ok(true);
+                }
+                if Instant::now() >= deadline {
                     break;
                 }
-                last_seen = current;
+                tokio::time::sleep(Duration::from_millis(50)).await;
             }
+            warn!(
+                "Server {} did not exit after being asked to, killing it",
+                pid
+            );
         }
-    });
+        Err(e) => {
+            // Nothing listening means the server is gone, and the PID may
+            // have been reused by another process
+            let refused = e
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused);
+            if refused {
+                debug!(
+                    "Nothing is listening on {}, not killing {}",
+                    endpoint.local_address(),
+                    pid
+                );
+                return Ok(false);
+            }
+            debug!("Could not stop server {} through its API: {}", pid, e);
+        }
+    }
 
-    // Handle any incoming messages (not really needed for our use case)
-    while let Some(result) = ws_rx.next().await {
-        match result {
-            Ok(_) => (),
-            Err(e) => {
-                debug!("WebSocket error: {}", e);
-                break;
+    utils::process::kill_process(pid)?;
+    Ok(true)
+}
+
+// Who may use a server's routes. A server bound to a non-loopback address
+// has an access token, given to the browser in the share URL and then kept
+// in a cookie; local marv commands use the control token instead.
+#[derive(Clone)]
+struct Access {
+    token: Option<String>,
+    control_token: String,
+    // Cookie holding the access token, named per port as cookies are shared
+    // between the ports of a host
+    cookie: String,
+    // Whether the server uses TLS, so the cookie is never sent over plain
+    // HTTP to the same host and port
+    secure: bool,
+}
+
+impl Access {
+    // Whether a request carries the access token in its query or cookie, or
+    // the control token in its Authorization header
+    fn allows(
+        &self,
+        query: &HashMap<String, String>,
+        cookie: Option<&str>,
+        authorization: Option<&str>,
+    ) -> bool {
+        let token = match &self.token {
+            Some(token) => token,
+            None => return true,
+        };
+
+        query
+            .get("token")
+            .is_some_and(|given| secret_matches(given, token))
+            || cookie.is_some_and(|cookie| {
+                cookie.split(';').any(|pair| {
+                    pair.trim().split_once('=').is_some_and(|(name, value)| {
+                        name == self.cookie && secret_matches(value, token)
+                    })
+                })
+            })
+            || bearer_matches(authorization, &self.control_token)
+    }
+
+    // Remember the access token in the browser, so that links, images and
+    // the WebSocket work without it in their URLs
+    fn with_cookie(&self, reply: impl Reply) -> warp::reply::Response {
+        let mut response = reply.into_response();
+        if let Some(token) = &self.token {
+            let mut cookie = format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Strict",
+                self.cookie, token
+            );
+            if self.secure {
+                cookie.push_str("; Secure");
+            }
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                response.headers_mut().insert(header::SET_COOKIE, value);
             }
         }
+        response
     }
+}
 
-    debug!("WebSocket connection closed");
+// A request without the access token
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// A WebSocket upgrade from a page served by another site
+#[derive(Debug)]
+struct CrossOrigin;
+
+impl warp::reject::Reject for CrossOrigin {}
+
+// A request for a host name that isn't this server's
+#[derive(Debug)]
+struct UnknownHost;
+
+impl warp::reject::Reject for UnknownHost {}
+
+// Compare a secret in constant time, so response times don't reveal how
+// much of a guess was right
+fn secret_matches(given: &str, secret: &str) -> bool {
+    given.as_bytes().ct_eq(secret.as_bytes()).into()
+}
+
+// Whether an Authorization header carries the given bearer token
+fn bearer_matches(authorization: Option<&str>, token: &str) -> bool {
+    authorization
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|given| secret_matches(given, token))
+}
+
+// Reject requests whose Host is not localhost, one of the server's addresses
+// or a configured name, so a site whose name is rebound to this machine's
+// address can't read the preview. Requests without a Host are allowed.
+fn known_host(
+    bind: IpAddr,
+    names: &[String],
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let mut addresses = vec![bind];
+    if bind.is_unspecified() {
+        addresses.extend(lan_address());
+    }
+    let addresses = Arc::new(addresses);
+    let names = Arc::new(names.to_vec());
+
+    warp::host::optional()
+        .and_then(move |host: Option<Authority>| {
+            let allowed = host
+                .as_ref()
+                .is_none_or(|host| is_known_host(host.host(), &addresses, &names));
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    debug!("Refused request for host {:?}", host);
+                    Err(warp::reject::custom(UnknownHost))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Whether a host name (without the port) names this server
+fn is_known_host(host: &str, addresses: &[IpAddr], names: &[String]) -> bool {
+    if host.eq_ignore_ascii_case("localhost")
+        || names.iter().any(|name| host.eq_ignore_ascii_case(name))
+    {
+        return true;
+    }
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => ip.is_loopback() || addresses.contains(&ip),
+        Err(_) => false,
+    }
+}
+
+// Reject requests that the access rules don't allow
+fn access_filter(access: Access) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or(warp::any().map(HashMap::new))
+        .unify()
+        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: HashMap<String, String>,
+                  cookie: Option<String>,
+                  authorization: Option<String>| {
+                let allowed = access.allows(&query, cookie.as_deref(), authorization.as_deref());
+                async move {
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(Unauthorized))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+// Reject WebSocket upgrades whose Origin is not this server, so pages from
+// other sites (including other local servers) can't follow the preview.
+// Clients that send no Origin (browsers always send one) are allowed.
+fn same_origin() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    // The host comes from the Host header, or the :authority of HTTP/2
+    // requests made over TLS
+    warp::header::optional::<String>("origin")
+        .and(warp::host::optional())
+        .and_then(
+            |origin: Option<String>, host: Option<Authority>| async move {
+                let allowed = match (&origin, &host) {
+                    (None, _) => true,
+                    (Some(origin), Some(host)) => {
+                        origin.split_once("://").is_some_and(|(_, origin_host)| {
+                            origin_host.eq_ignore_ascii_case(host.as_str())
+                        })
+                    }
+                    (Some(_), None) => false,
+                };
+                if allowed {
+                    Ok(())
+                } else {
+                    debug!("Refused WebSocket from {:?} to {:?}", origin, host);
+                    Err(warp::reject::custom(CrossOrigin))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+// Headers added to every response. Files served from the document's
+// directory, such as HTML or SVG files it links to, can't run scripts unless
+// the document is trusted.
+fn with_security_headers(
+    mut response: warp::reply::Response,
+    trusted: bool,
+) -> warp::reply::Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    if !trusted && !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(FILES_POLICY),
+        );
+    }
+    response
+}
+
+// Turn access rejections into responses, leaving the rest to warp
+async fn handle_rejection(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let (status, message) = if rejection.find::<Unauthorized>().is_some() {
+        (
+            StatusCode::UNAUTHORIZED,
+            "This preview is shared with an access token; open the link including ?token=",
+        )
+    } else if rejection.find::<CrossOrigin>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "Cross-origin WebSocket connections are not allowed",
+        )
+    } else if rejection.find::<UnknownHost>().is_some() {
+        (
+            StatusCode::FORBIDDEN,
+            "The preview is only served at localhost and the server's addresses",
+        )
+    } else {
+        return Err(rejection);
+    };
+    Ok(warp::reply::with_status(message, status).into_response())
+}
+
+// The access token for a server bound to an address other than loopback,
+// created on first use and kept next to the server's record
+fn access_token(server_info_path: &Path, bind: IpAddr) -> Result<Option<String>> {
+    if bind.is_loopback() {
+        return Ok(None);
+    }
+    if let Ok(token) = utils::file::read_server_secret(server_info_path, ACCESS_TOKEN) {
+        return Ok(Some(token));
+    }
+    let token = generate_token();
+    utils::file::write_server_secret(server_info_path, ACCESS_TOKEN, &token)?;
+    Ok(Some(token))
+}
+
+// Where a server listens
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+    bind: IpAddr,
+    port: u16,
+    tls: bool,
+}
+
+impl Endpoint {
+    // The endpoint recorded for a server
+    fn from_record(server_info_path: &Path, port: u16) -> Endpoint {
+        let (bind, tls) = utils::file::read_server_endpoint(server_info_path);
+        Endpoint { bind, port, tls }
+    }
+
+    // The address local commands use to reach the server
+    fn local_address(&self) -> SocketAddr {
+        let ip = match self.bind {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        SocketAddr::new(ip, self.port)
+    }
+
+    // The URL to open the preview on this machine
+    fn preview_url(&self, access_token: Option<&str>) -> String {
+        let addr = self.local_address();
+        let host = if addr.ip().is_loopback() {
+            format!("localhost:{}", self.port)
+        } else {
+            addr.to_string()
+        };
+        self.url(&host, access_token)
+    }
+
+    // The URL to open the preview from other machines, for a shared server
+    fn share_url(&self, access_token: Option<&str>) -> Option<String> {
+        if self.bind.is_loopback() {
+            return None;
+        }
+        let ip = if self.bind.is_unspecified() {
+            lan_address()?
+        } else {
+            self.bind
+        };
+        Some(self.url(&SocketAddr::new(ip, self.port).to_string(), access_token))
+    }
+
+    fn url(&self, host: &str, access_token: Option<&str>) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        match access_token {
+            Some(token) => format!("{}://{}/?token={}", scheme, host, token),
+            None => format!("{}://{}/", scheme, host),
+        }
+    }
+
+    // Tell the user where to find the preview, and how to share it
+    fn print_urls(&self, access_token: Option<&str>) {
+        println!("Preview available at {}", self.preview_url(access_token));
+        if let Some(url) = self.share_url(access_token) {
+            println!("Shared on your network at {}", url);
+            println!("Anyone with this link can read the document");
+        }
+    }
+}
+
+// This machine's address on the network used for the default route. The
+// socket is only connected, so nothing is sent.
+fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// State shared by the WebSocket connections to one server
+#[derive(Clone)]
+struct Clients {
+    // Identifies the previewed document to the browser
+    doc_id: String,
+    // Distinguishes renders of this server from those of earlier runs
+    instance: String,
+    // Scroll and goto messages relayed between tabs, tagged with the sender
+    relay: broadcast::Sender<(usize, ServerMessage)>,
+    next_id: Arc<AtomicUsize>,
+    // Number of open connections
+    connected: Arc<watch::Sender<usize>>,
+}
+
+impl Clients {
+    fn new(doc_id: &str) -> Clients {
+        let (relay, _) = broadcast::channel(16);
+        let started = format!("{}:{:?}", std::process::id(), SystemTime::now());
+        Clients {
+            doc_id: doc_id.to_string(),
+            instance: format!("{:x}", md5::compute(started))[..8].to_string(),
+            relay,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            connected: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    // Send a message from the server to every connected page
+    fn broadcast(&self, message: ServerMessage) {
+        let _ = self.relay.send((usize::MAX, message));
+    }
+
+    // Tell every connected page that the server is going away
+    fn say_goodbye(&self, reason: &str) {
+        self.broadcast(ServerMessage::Bye {
+            reason: reason.to_string(),
+        });
+    }
+
+    // Identify a published revision of the document, so pages can tell
+    // whether they are showing the latest render
+    fn render_id(&self, revision: u64) -> String {
+        format!("{}-{}", self.instance, revision)
+    }
+}
+
+// The /ws route, upgrading connections to the preview protocol
+fn ws_route(
+    file_updates: watch::Receiver<FileState>,
+    clients: Clients,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(same_origin())
+        .and(warp::ws())
+        .and(warp::any().map(move || file_updates.clone()))
+        .and(warp::any().map(move || clients.clone()))
+        .map(|ws: warp::ws::Ws, content, clients| {
+            ws.on_upgrade(move |websocket| handle_ws_connection(websocket, content, clients))
+        })
+}
+
+// Queue a message for a websocket, returning false once the client has gone.
+// The connection is closed after a goodbye.
+fn send_message(
+    tx: &mpsc::UnboundedSender<Result<Message, warp::Error>>,
+    message: ServerMessage,
+) -> bool {
+    let bye = matches!(message, ServerMessage::Bye { .. });
+    let sent = tx.send(Ok(Message::text(message.to_json()))).is_ok();
+    if bye {
+        let _ = tx.send(Ok(Message::close()));
+        return false;
+    }
+    sent
+}
+
+// Handle websocket connections. See protocol.rs for the messages exchanged.
+async fn handle_ws_connection(
+    ws: WebSocket,
+    mut file_updates: watch::Receiver<FileState>,
+    clients: Clients,
+) {
+    let id = clients.next_id.fetch_add(1, Ordering::SeqCst);
+    let _connection = Connection::open(&clients.connected);
+
+    // Split the websocket into sender and receiver
+    let (ws_tx, mut ws_rx) = ws.split();
+
+    // Create a channel for messages to send to the websocket
+    let (tx, rx) = mpsc::unbounded_channel();
+    let rx = UnboundedReceiverStream::new(rx);
+
+    // Forward messages from the channel to the websocket
+    tokio::task::spawn(rx.forward(ws_tx));
+
+    // Note what the client has seen before greeting it, so nothing sent
+    // after the greeting is missed
+    let mut last_seen = file_updates.borrow_and_update().clone();
+    let mut relayed = clients.relay.subscribe();
+    let relay_clients = clients.clone();
+    send_message(
+        &tx,
+        ServerMessage::hello(&clients.doc_id, &clients.render_id(last_seen.revision)),
+    );
+
+    // Spawn a task to watch for file changes
+    let tx_clone = tx.clone();
+    let update_clients = clients.clone();
+    let updates = tokio::task::spawn(async move {
+        let clients = update_clients;
+        while file_updates.changed().await.is_ok() {
+            let current = file_updates.borrow().clone();
+            if current != last_seen {
+                // A deleted or unreadable file keeps its last content on screen;
+                // anything else is a refresh
+                let message = match &current.error {
+                    Some(error) if !current.deleted => {
+                        debug!("Render failed, sending error notification");
+                        ServerMessage::error(error, Severity::Error)
+                    }
+                    _ => {
+                        debug!("File changed, sending content notification");
+                        ServerMessage::Content {
+                            render_id: clients.render_id(current.revision),
+                            deleted: current.deleted,
+                        }
+                    }
+                };
+                if !send_message(&tx_clone, message) {
+                    // Client disconnected
+                    return;
+                }
+                last_seen = current;
+            }
+        }
+
+        // The watcher is gone, so the server is shutting down
+        send_message(
+            &tx_clone,
+            ServerMessage::Bye {
+                reason: "the preview server has stopped".to_string(),
+            },
+        );
+    });
+
+    // Pass on what other tabs relay
+    let tx_clone = tx.clone();
+    let relay = tokio::task::spawn(async move {
+        loop {
+            match relayed.recv().await {
+                Ok((from, message)) if from != id => {
+                    if !send_message(&tx_clone, message) {
+                        break;
+                    }
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => (),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    // Handle messages from the browser
+    while let Some(result) = ws_rx.next().await {
+        let message = match result {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("WebSocket error: {}", e);
+                break;
+            }
+        };
+        let text = match message.to_str() {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+
+        match ClientMessage::parse(text) {
+            Some(ClientMessage::Hello { protocol }) if protocol != PROTOCOL_VERSION => {
+                info!(
+                    "Client {} speaks protocol {}, server speaks {}",
+                    id, protocol, PROTOCOL_VERSION
+                );
+            }
+            Some(ClientMessage::Hello { protocol }) => {
+                debug!("Client {} speaks protocol {}", id, protocol);
+            }
+            Some(ClientMessage::Scroll { line }) => {
+                let _ = relay_clients
+                    .relay
+                    .send((id, ServerMessage::Scroll { line }));
+            }
+            Some(ClientMessage::Goto { line }) => {
+                let _ = relay_clients.relay.send((id, ServerMessage::Goto { line }));
+            }
+            None => debug!("Ignoring unrecognised message: {}", text),
+        }
+    }
+
+    updates.abort();
+    relay.abort();
+    debug!("WebSocket connection closed");
+}
+
+// The files the rendered page references, such as images, served from the
+// document's directory. Nothing else there is served: not other files, not
+// dotfiles, and nothing a symlink leads to outside the directory.
+fn files_route(
+    document_dir: &Path,
+    file_updates: watch::Receiver<FileState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let document_dir =
+        fs::canonicalize(document_dir).unwrap_or_else(|_| document_dir.to_path_buf());
+    let checked_dir = document_dir.clone();
+    warp::path::peek()
+        .and(warp::any().map(move || file_updates.clone()))
+        .and_then(
+            move |path: warp::path::Peek, state: watch::Receiver<FileState>| {
+                let allowed =
+                    is_referenced_file(&checked_dir, path.as_str(), &state.borrow().dependencies);
+                async move {
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::not_found())
+                    }
+                }
+            },
+        )
+        .untuple_one()
+        .and(warp::fs::dir(document_dir))
+}
+
+// Whether a request path names a file inside the document's directory that
+// the page depends on
+fn is_referenced_file(
+    document_dir: &Path,
+    request_path: &str,
+    dependencies: &BTreeSet<PathBuf>,
+) -> bool {
+    let relative = PathBuf::from(renderer::percent_decode(request_path));
+    let hidden = relative.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => true,
+    });
+    if hidden {
+        return false;
+    }
+    match fs::canonicalize(document_dir.join(&relative)) {
+        Ok(path) => path.starts_with(document_dir) && dependencies.contains(&path),
+        Err(_) => false,
+    }
+}
+
+// The preview page: the rendered document with the live reload script, and
+// a Content-Security-Policy allowing only its own scripts and Mermaid's when
+// a Mermaid URL is given
+fn page_route(
+    file_updates: watch::Receiver<FileState>,
+    clients: Clients,
+    mermaid_url: Option<String>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::any().map(move || file_updates.clone()))
+        .map(move |content: watch::Receiver<FileState>| {
+            let current_state = content.borrow().clone();
+            let mut html_content = current_state.html;
+
+            // Label the page with its document and render, so the script can
+            // tell when it is stale or the port serves another document
+            let render_id = clients.render_id(current_state.revision);
+            html_content = html_content.replacen(
+                "</head>",
+                &format!(
+                    r#"<meta name="marv-doc-id" content="{}"><meta name="marv-render-id" content="{}"></head>"#,
+                    clients.doc_id, render_id
+                ),
+                1,
+            );
+
+            // Insert live reload JavaScript before the closing body tag
+            let script = r#"
+<script>
+    // Version of the WebSocket message schema this script understands
+    const MARV_PROTOCOL = PROTOCOL_VERSION;
+
+    // How long to wait before reconnecting to a server that has gone away,
+    // and, less often, to one that said goodbye
+    const RECONNECT_DELAY = 2000;
+    const STOPPED_RECONNECT_DELAY = 10000;
+
+    // WebSocket connection, replaced on reconnection
+    let socket = null;
+    // Set once the server has said goodbye or the connection has dropped
+    let stopped = false;
+    // Set once the server has said goodbye, after which the page reconnects
+    // less often and keeps saying the server stopped
+    let goodbye = false;
+    // Set while the page scrolls itself, so that isn't relayed to other tabs
+    let applyingScroll = false;
+    let applyingTimer = null;
+
+    // The document and render this page shows
+    const docMeta = document.querySelector('meta[name="marv-doc-id"]');
+    const docId = docMeta ? docMeta.content : null;
+    const renderMeta = document.querySelector('meta[name="marv-render-id"]');
+    const renderId = renderMeta ? renderMeta.content : null;
+
+    // Show a status banner over the last rendered content
+    function showStatus(message) {
+        let banner = document.getElementById('marv-status');
+        if (!banner) {
+            banner = document.createElement('div');
+            banner.id = 'marv-status';
+            banner.className = 'status-banner';
+            document.body.appendChild(banner);
+        }
+        banner.textContent = message;
+        banner.classList.add('visible');
+    }
+
+    function hideStatus() {
+        const banner = document.getElementById('marv-status');
+        if (banner) {
+            banner.classList.remove('visible');
+        }
+    }
+
+    // Show a render error or warning, keeping the last good render underneath
+    function showError(error) {
+        if (window.marvShowError) {
+            const source = error.severity === 'warning' ? 'Warning' : 'Preview not updated';
+            window.marvShowError(source, error.message, error.line);
+        } else {
+            showStatus(error.message);
+        }
+    }
+
+    // Markers recording the source line each top-level block starts on
+    function sourceLines() {
+        return Array.from(document.querySelectorAll('[data-source-line]'));
+    }
+
+    // The source line of the block at the top of the window, and how far
+    // the window has scrolled past the start of that block
+    function currentPosition() {
+        let position = null;
+        for (const marker of sourceLines()) {
+            const top = marker.getBoundingClientRect().top;
+            if (top > 1) break;
+            position = { line: Number(marker.dataset.sourceLine), offset: -top };
+        }
+        return position;
+    }
+
+    function currentLine() {
+        const position = currentPosition();
+        return position ? position.line : null;
+    }
+
+    // Keep the reading position and focus across reloads of this tab,
+    // anchored to a source block so edits above it don't shift the view
+    const positionKey = 'marv-position:' + window.location.pathname;
+    function savePosition() {
+        const focused = document.activeElement;
+        sessionStorage.setItem(positionKey, JSON.stringify({
+            block: currentPosition(),
+            scrollY: window.scrollY,
+            focus: focused && focused.id ? focused.id : null,
+        }));
+    }
+
+    function restorePosition() {
+        let saved;
+        try {
+            saved = JSON.parse(sessionStorage.getItem(positionKey));
+        } catch (e) {
+            return;
+        }
+        if (!saved) return;
+
+        let target = null;
+        if (saved.block) {
+            for (const marker of sourceLines()) {
+                if (Number(marker.dataset.sourceLine) > saved.block.line) break;
+                target = marker;
+            }
+        }
+        if (target) {
+            scrollQuietly(window.scrollY + target.getBoundingClientRect().top + saved.block.offset);
+        } else {
+            scrollQuietly(saved.scrollY);
+        }
+
+        const focused = saved.focus && document.getElementById(saved.focus);
+        if (focused) {
+            focused.focus({ preventScroll: true });
+        }
+    }
+
+    function reload() {
+        savePosition();
+        window.location.reload();
+    }
+
+    // Restore once the page is parsed, and again once images and diagrams
+    // have been laid out
+    if ('scrollRestoration' in history) {
+        history.scrollRestoration = 'manual';
+    }
+    window.addEventListener('beforeunload', savePosition);
+    window.addEventListener('load', function() {
+        restorePosition();
+        setTimeout(restorePosition, 300);
+    });
+    restorePosition();
+
+    // Scroll to the block containing a source line, optionally highlighting it
+    function scrollToLine(line, highlight) {
+        let target = null;
+        for (const marker of sourceLines()) {
+            if (Number(marker.dataset.sourceLine) > line) break;
+            target = marker;
+        }
+        if (!target) return;
+        scrollQuietly(window.scrollY + target.getBoundingClientRect().top);
+        const block = target.nextElementSibling;
+        if (highlight && block) {
+            block.classList.add('source-highlight');
+            setTimeout(() => block.classList.remove('source-highlight'), 1500);
+        }
+    }
+
+    function send(message) {
+        if (socket && socket.readyState === WebSocket.OPEN) {
+            socket.send(JSON.stringify(message));
+        }
+    }
+
+    // Scroll without telling the other tabs. The flag is cleared after a
+    // while rather than by the next scroll event, which never comes when the
+    // page is already in place.
+    function scrollQuietly(y) {
+        applyingScroll = true;
+        clearTimeout(applyingTimer);
+        try {
+            window.scrollTo(0, y);
+        } finally {
+            applyingTimer = setTimeout(function() {
+                applyingScroll = false;
+            }, 300);
+        }
+    }
+
+    // Tell the other tabs where this one has scrolled to
+    let scrollTimer = null;
+    window.addEventListener('scroll', function() {
+        clearTimeout(scrollTimer);
+        if (applyingScroll) return;
+        scrollTimer = setTimeout(function() {
+            const line = currentLine();
+            if (line !== null) {
+                send({ type: 'scroll', line: line });
+            }
+        }, 150);
+    });
+
+    // Handle messages from the server
+    function handleMessage(event) {
+        let message;
+        try {
+            message = JSON.parse(event.data);
+        } catch (e) {
+            console.error('Invalid message from server:', event.data);
+            return;
+        }
+
+        switch (message.type) {
+            case 'hello':
+                // Ports are reused across restarts, so another document's server
+                // may have taken this one's; keep waiting for this document
+                if (docId && message.doc_id !== docId) {
+                    stopped = true;
+                    showStatus('Another document is previewed at this address. Waiting for this one to return...');
+                    socket.close();
+                    break;
+                }
+                // A server speaking another protocol serves a matching script,
+                // and a restarted server may have rendered newer content
+                if (message.protocol !== MARV_PROTOCOL || message.render_id !== renderId) {
+                    console.log('Page is stale, reloading');
+                    reload();
+                } else if (stopped) {
+                    stopped = false;
+                    goodbye = false;
+                    hideStatus();
+                }
+                break;
+            case 'content':
+                if (message.deleted) {
+                    console.log('File was deleted');
+                    showStatus(FILE_DELETED_MESSAGE);
+                } else if (message.render_id !== renderId) {
+                    console.log('Refreshing page due to file change');
+                    reload();
+                }
+                break;
+            case 'error':
+                console.log('Failed to render file:', message.message);
+                showError(message);
+                break;
+            case 'scroll':
+                scrollToLine(message.line, false);
+                break;
+            case 'goto':
+                scrollToLine(message.line, true);
+                break;
+            case 'focus':
+                focusPage();
+                break;
+            case 'bye':
+                stopped = true;
+                goodbye = true;
+                showStatus('Server stopped: ' + message.reason + '. The page reconnects when it is started again.');
+                break;
+        }
+    }
+
+    // Bring the page to the front when the preview is opened again. Browsers
+    // may not allow that, so the title is marked until the page is looked at.
+    function focusPage() {
+        window.focus();
+        if (document.hidden && !document.title.startsWith('\u25CF ')) {
+            document.title = '\u25CF ' + document.title;
+            document.addEventListener('visibilitychange', function unmark() {
+                if (!document.hidden) {
+                    document.title = document.title.replace(/^\u25CF /, '');
+                    document.removeEventListener('visibilitychange', unmark);
+                }
+            });
+        }
+    }
+
+    // Connect to the WebSocket server. When the connection drops the page is
+    // kept and the connection retried, quietly and less often if the server
+    // said goodbye; the server's greeting then tells whether the page needs
+    // reloading.
+    function connect() {
+        const scheme = window.location.protocol === 'https:' ? 'wss' : 'ws';
+        socket = new WebSocket(`${scheme}://${window.location.host}/ws`);
+        socket.onmessage = handleMessage;
+
+        // Announce the protocol this script speaks
+        socket.onopen = function() {
+            console.log('WebSocket connected for live reload');
+            send({ type: 'hello', protocol: MARV_PROTOCOL });
+        };
+
+        // Handle connection close
+        socket.onclose = function() {
+            console.log('WebSocket connection closed');
+            if (goodbye) {
+                setTimeout(connect, STOPPED_RECONNECT_DELAY);
+                return;
+            }
+            if (!stopped) {
+                stopped = true;
+                showStatus('Connection to the preview server lost. Reconnecting...');
+            }
+            setTimeout(connect, RECONNECT_DELAY);
+        };
+
+        // Handle connection errors
+        socket.onerror = function(error) {
+            console.error('WebSocket error:', error);
+        };
+    }
+
+    connect();
+</script>
+</body>
+"#;
+
+            // Show the deleted banner straight away if the page is loaded while the file is missing
+            let banner = if current_state.deleted {
+                format!(
+                    r#"<div id="marv-status" class="status-banner visible">{}</div>"#,
+                    FILE_DELETED_MESSAGE
+                )
+            } else {
+                String::new()
+            };
+            let script = script
+                .replace(
+                    "FILE_DELETED_MESSAGE",
+                    &format!("'{}'", FILE_DELETED_MESSAGE),
+                )
+                .replace("PROTOCOL_VERSION", &PROTOCOL_VERSION.to_string())
+                .replacen(
+                    "<script>",
+                    &format!(r#"<script nonce="{}">"#, current_state.nonce),
+                    1,
+                );
+
+            // Report an error that happened before the page was loaded, or a
+            // warning about the rendered content
+            let reported = match (&current_state.error, &current_state.warning) {
+                (Some(error), _) if !current_state.deleted => {
+                    Some(ServerMessage::error(error, Severity::Error))
+                }
+                (None, Some(warning)) => Some(ServerMessage::error(warning, Severity::Warning)),
+                _ => None,
+            };
+            let script = match reported {
+                Some(message) => script.replace(
+                    "</script>",
+                    &format!(
+                        "    showError({});\n</script>",
+                        message.to_json().replace("</", "<\\/")
+                    ),
+                ),
+                None => script,
+            };
+
+            // Replace the closing body tag with our script + closing body tag.
+            // Raw HTML and templates can contain others, so only the last is
+            // the page's
+            if let Some(index) = html_content.rfind("</body>") {
+                html_content.replace_range(index..index + "</body>".len(), &(banner + &script));
+            }
+
+            let mut response = warp::reply::html(html_content).into_response();
+            if let Some(mermaid_url) = &mermaid_url {
+                let policy =
+                    renderer::content_security_policy(&current_state.nonce, mermaid_url);
+                if let Ok(value) = HeaderValue::from_str(&policy) {
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_SECURITY_POLICY, value);
+                }
+            }
+            response
+        })
+}
+
+// Counts an open connection until it is dropped, however the connection ends
+struct Connection(Arc<watch::Sender<usize>>);
+
+impl Connection {
+    fn open(connected: &Arc<watch::Sender<usize>>) -> Connection {
+        connected.send_modify(|count| *count += 1);
+        Connection(connected.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::RenderError;
+
+    // Open a WebSocket to the /ws route and return the decoded greeting
+    async fn connect(
+        file_updates: &watch::Receiver<FileState>,
+        clients: &Clients,
+    ) -> (warp::test::WsClient, ServerMessage) {
+        let mut client = warp::test::ws()
+            .path("/ws")
+            .handshake(ws_route(file_updates.clone(), clients.clone()))
+            .await
+            .expect("handshake");
+        let hello = recv(&mut client).await;
+        (client, hello)
+    }
+
+    // Receive and decode the next server message
+    async fn recv(client: &mut warp::test::WsClient) -> ServerMessage {
+        let message = tokio::time::timeout(Duration::from_secs(2), client.recv())
+            .await
+            .expect("timed out waiting for a message")
+            .expect("connection closed");
+        serde_json::from_str(message.to_str().expect("text message")).expect("valid message")
+    }
+
+    #[tokio::test]
+    async fn greets_clients_with_protocol_and_document() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let clients = Clients::new("abc123");
+        let (_client, hello) = connect(&rx, &clients).await;
+
+        assert_eq!(
+            hello,
+            ServerMessage::Hello {
+                protocol: PROTOCOL_VERSION,
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                doc_id: "abc123".to_string(),
+                render_id: clients.render_id(0),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_content_when_the_file_changes() {
+        let (tx, rx) = watch::channel(FileState::default());
+        let clients = Clients::new("doc");
+        let (mut client, _) = connect(&rx, &clients).await;
+        client
+            .send_text(&format!(
+                r#"{{"type":"hello","protocol":{}}}"#,
+                PROTOCOL_VERSION
+            ))
+            .await;
+
+        tx.send_modify(|state| {
+            state.html = "<p>changed</p>".to_string();
+            state.revision = 1;
+        });
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Content {
+                render_id: clients.render_id(1),
+                deleted: false,
+            }
+        );
+
+        tx.send_modify(|state| {
+            state.deleted = true;
+            state.revision = 2;
+        });
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Content {
+                render_id: clients.render_id(2),
+                deleted: true,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_errors_instead_of_content() {
+        let (tx, rx) = watch::channel(FileState::default());
+        let (mut client, _) = connect(&rx, &Clients::new("doc")).await;
+
+        tx.send_modify(|state| {
+            state.error = Some(RenderError {
+                message: "Failed to read file".to_string(),
+                line: Some(3),
+            });
+            state.revision = 1;
+        });
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Error {
+                severity: Severity::Error,
+                message: "Failed to read file".to_string(),
+                line: Some(3),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_scroll_and_goto_to_other_tabs() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let clients = Clients::new("doc");
+        let (mut first, _) = connect(&rx, &clients).await;
+        let (mut second, _) = connect(&rx, &clients).await;
+
+        // Unknown messages are ignored rather than closing the connection
+        first.send_text(r#"{"type":"unknown"}"#).await;
+        first.send_text(r#"{"type":"scroll","line":12}"#).await;
+        assert_eq!(recv(&mut second).await, ServerMessage::Scroll { line: 12 });
+
+        second.send_text(r#"{"type":"goto","line":40}"#).await;
+        assert_eq!(recv(&mut first).await, ServerMessage::Goto { line: 40 });
+    }
+
+    #[tokio::test]
+    async fn says_bye_when_the_watcher_stops() {
+        let (tx, rx) = watch::channel(FileState::default());
+        let (mut client, _) = connect(&rx, &Clients::new("doc")).await;
+
+        drop(tx);
+        assert!(matches!(recv(&mut client).await, ServerMessage::Bye { .. }));
+    }
+
+    #[tokio::test]
+    async fn serves_only_files_the_page_references() {
+        let dir = fs::canonicalize(utils::file::test_dir("files-route")).unwrap();
+        for name in ["image.png", "notes.txt", ".env"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let mut dependencies = BTreeSet::from([dir.join("image.png"), dir.join(".env")]);
+
+        // A referenced symlink out of the directory isn't followed
+        #[cfg(target_family = "unix")]
+        {
+            let outside = utils::file::test_dir("files-route-outside").join("secret.txt");
+            fs::write(&outside, "secret").unwrap();
+            std::os::unix::fs::symlink(&outside, dir.join("link.png")).unwrap();
+            dependencies.insert(fs::canonicalize(&outside).unwrap());
+        }
+
+        let (_tx, rx) = watch::channel(FileState {
+            dependencies,
+            ..FileState::default()
+        });
+        let routes = files_route(&dir, rx);
+        let status = |path: &'static str| {
+            let routes = routes.clone();
+            async move {
+                warp::test::request()
+                    .path(path)
+                    .reply(&routes)
+                    .await
+                    .status()
+            }
+        };
+
+        assert_eq!(status("/image.png").await, StatusCode::OK);
+        assert_eq!(status("/notes.txt").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/.env").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/link.png").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/%2e%2e/etc/passwd").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn sends_security_headers() {
+        let dir = fs::canonicalize(utils::file::test_dir("security-headers")).unwrap();
+        fs::write(dir.join("page.html"), "<script>alert(1)</script>").unwrap();
+        let (_tx, rx) = watch::channel(FileState {
+            html: "<html><head></head><body><p>text</p></body></html>".to_string(),
+            dependencies: BTreeSet::from([dir.join("page.html")]),
+            nonce: "n0nce".to_string(),
+            ..FileState::default()
+        });
+        // The routes as a server assembles them, for a trusted document or not
+        fn secured(reply: impl Reply, trusted: bool) -> warp::reply::Response {
+            with_security_headers(reply.into_response(), trusted)
+        }
+        let routes = |trusted: bool| {
+            let mermaid_url = (!trusted).then(|| "https://cdn.example/mermaid.js".to_string());
+            page_route(rx.clone(), Clients::new("doc"), mermaid_url)
+                .or(files_route(&dir, rx.clone()))
+                .map(move |reply| secured(reply, trusted))
+        };
+        let header = |response: &warp::http::Response<_>, name| {
+            response
+                .headers()
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap().to_string())
+        };
+
+        let page = warp::test::request().path("/").reply(&routes(false)).await;
+        let policy = header(&page, header::CONTENT_SECURITY_POLICY).unwrap();
+        assert!(policy.contains("script-src 'nonce-n0nce' https://cdn.example/mermaid.js;"));
+        assert!(String::from_utf8_lossy(page.body()).contains(r#"<script nonce="n0nce">"#));
+        assert_eq!(
+            header(&page, header::X_CONTENT_TYPE_OPTIONS).as_deref(),
+            Some("nosniff")
+        );
+
+        let file = warp::test::request()
+            .path("/page.html")
+            .reply(&routes(false))
+            .await;
+        assert_eq!(file.status(), StatusCode::OK);
+        assert_eq!(
+            header(&file, header::CONTENT_SECURITY_POLICY).as_deref(),
+            Some(FILES_POLICY)
+        );
+        assert_eq!(
+            header(&file, header::X_CONTENT_TYPE_OPTIONS).as_deref(),
+            Some("nosniff")
+        );
+
+        // Trusted documents run their scripts, on the page and in its files
+        for path in ["/", "/page.html"] {
+            let response = warp::test::request().path(path).reply(&routes(true)).await;
+            assert_eq!(header(&response, header::CONTENT_SECURITY_POLICY), None);
+            assert_eq!(
+                header(&response, header::X_CONTENT_TYPE_OPTIONS).as_deref(),
+                Some("nosniff")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn adds_the_page_script_once() {
+        let (_tx, rx) = watch::channel(FileState {
+            html: "<html><head></head><body><pre></body></pre><p>text</p></body></html>"
+                .to_string(),
+            nonce: "n0nce".to_string(),
+            ..FileState::default()
+        });
+        let routes = page_route(rx, Clients::new("doc"), None);
+
+        let page = warp::test::request().path("/").reply(&routes).await;
+        let body = String::from_utf8_lossy(page.body());
+        assert_eq!(body.matches(r#"<script nonce="n0nce">"#).count(), 1);
+        assert!(body.contains("<pre></body></pre><p>text</p>"));
+        assert!(body.ends_with("</script>\n</body>\n</html>"));
+    }
+
+    #[tokio::test]
+    async fn idles_only_with_a_timeout() {
+        let (_connected, rx) = watch::channel(0);
+        let waited = tokio::time::timeout(
+            Duration::from_millis(200),
+            wait_until_idle(rx, Duration::ZERO),
+        )
+        .await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn idles_once_no_client_is_connected() {
+        let (connected, rx) = watch::channel(0);
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            wait_until_idle(rx.clone(), Duration::from_millis(50)),
+        )
+        .await
+        .expect("idle without clients");
+
+        // Not while a client is connected, however long
+        connected.send_replace(1);
+        let waited = tokio::time::timeout(
+            Duration::from_millis(300),
+            wait_until_idle(rx.clone(), Duration::from_millis(50)),
+        )
+        .await;
+        assert!(waited.is_err());
+
+        // Then again once it has gone
+        let idle = tokio::spawn(wait_until_idle(rx, Duration::from_millis(50)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        connected.send_replace(0);
+        tokio::time::timeout(Duration::from_secs(2), idle)
+            .await
+            .expect("idle after the client left")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_connections_until_they_close() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let clients = Clients::new("doc");
+        let (client, _) = connect(&rx, &clients).await;
+        let (other, _) = connect(&rx, &clients).await;
+        assert_eq!(*clients.connected.borrow(), 2);
+
+        drop(client);
+        drop(other);
+        let mut connected = clients.connected.subscribe();
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            connected.wait_for(|count| *count == 0),
+        )
+        .await
+        .expect("connections closed")
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn says_goodbye_and_closes_on_shutdown() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let clients = Clients::new("doc");
+        let (mut client, _) = connect(&rx, &clients).await;
+
+        clients.say_goodbye("stopped");
+        assert_eq!(
+            recv(&mut client).await,
+            ServerMessage::Bye {
+                reason: "stopped".to_string(),
+            }
+        );
+        assert!(client.recv_closed().await.is_ok());
+    }
+
+    // An API for a server previewing /tmp/doc.md with the given token
+    fn test_api(token: &str) -> Api {
+        Api {
+            path: PathBuf::from("/tmp/doc.md"),
+            port: 4000,
+            started: Instant::now(),
+            token: token.to_string(),
+            clients: Clients::new("doc"),
+            stop: Arc::new(Notify::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_health_and_info() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let routes = api_routes(test_api("secret"), rx);
+
+        let health = warp::test::request()
+            .path("/api/health")
+            .reply(&routes)
+            .await;
+        assert_eq!(health.status(), StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_slice(health.body()).unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["doc_id"], "doc");
+
+        let info = warp::test::request().path("/api/info").reply(&routes).await;
+        let info: serde_json::Value = serde_json::from_slice(info.body()).unwrap();
+        assert_eq!(info["path"], "/tmp/doc.md");
+        assert_eq!(info["port"], 4000);
+        assert_eq!(info["clients"], 0);
+        assert!(info.get("token").is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_only_with_the_token() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let api = test_api("secret");
+        let stop = api.stop.clone();
+        let routes = api_routes(api, rx);
+
+        for authorization in [None, Some("Bearer wrong")] {
+            let mut request = warp::test::request().method("POST").path("/api/stop");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/stop")
+            .header("authorization", "Bearer secret")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::time::timeout(Duration::from_secs(1), stop.notified())
+            .await
+            .expect("stop was not signalled");
+    }
+
+    #[tokio::test]
+    async fn focuses_connected_pages() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let api = test_api("secret");
+        let clients = api.clients.clone();
+        let routes = api_routes(api, rx.clone());
+        let focus = || {
+            warp::test::request()
+                .method("POST")
+                .path("/api/focus")
+                .header("authorization", "Bearer secret")
+        };
+
+        let response = focus().reply(&routes).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["clients"], 0);
+
+        let (mut client, _) = connect(&rx, &clients).await;
+        let response = focus().reply(&routes).await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["clients"], 1);
+        assert_eq!(recv(&mut client).await, ServerMessage::Focus);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_documents_port_unless_it_is_taken() {
+        let config = Config {
+            port_min: 47000,
+            port_max: 47999,
+            ..Config::default()
+        };
+        let path = Path::new("/tmp/doc.md");
+        let preferred = preferred_port(&config, path);
+        assert!((47000..=47999).contains(&preferred));
+        assert_eq!(preferred_port(&config, path), preferred);
+
+        // Held here, unless something else already has it
+        let _taken = std::net::TcpListener::bind(SocketAddr::new(config.bind, preferred));
+        let port = choose_port(&config, path, false).await.unwrap();
+        assert_ne!(port, preferred);
+        assert!((47000..=47999).contains(&port));
+    }
+
+    #[tokio::test]
+    async fn shared_servers_require_the_access_token() {
+        let access = Access {
+            token: Some("shared".to_string()),
+            control_token: "control".to_string(),
+            cookie: "marv_token_4000".to_string(),
+            secure: false,
+        };
+        let routes = access_filter(access)
+            .map(warp::reply)
+            .recover(handle_rejection);
+
+        let status = |request: warp::test::RequestBuilder| {
+            let routes = routes.clone();
+            async move { request.reply(&routes).await.status() }
+        };
+        assert_eq!(
+            status(warp::test::request().path("/")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(warp::test::request().path("/?token=wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(warp::test::request().path("/?token=shared")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                warp::test::request()
+                    .path("/")
+                    .header("cookie", "other=1; marv_token_4000=shared")
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                warp::test::request()
+                    .path("/")
+                    .header("authorization", "Bearer control")
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(
+                warp::test::request()
+                    .path("/")
+                    .header("cookie", "marv_token_4000=share")
+                    .header("authorization", "Bearer contro")
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn marks_the_cookie_secure_over_tls() {
+        let cookie = |secure| {
+            let access = Access {
+                token: Some("shared".to_string()),
+                control_token: "control".to_string(),
+                cookie: "marv_token_4000".to_string(),
+                secure,
+            };
+            let response = access.with_cookie(warp::reply());
+            response.headers()[header::SET_COOKIE]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            cookie(false),
+            "marv_token_4000=shared; Path=/; HttpOnly; SameSite=Strict"
+        );
+        assert_eq!(
+            cookie(true),
+            "marv_token_4000=shared; Path=/; HttpOnly; SameSite=Strict; Secure"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_only_to_its_own_host_names() {
+        let bind = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let names = ["docs.example".to_string()];
+        let routes = known_host(bind, &names)
+            .map(warp::reply)
+            .recover(handle_rejection);
+
+        for host in [
+            "localhost:4000",
+            "Docs.Example:4000",
+            "LOCALHOST",
+            "127.0.0.1:4000",
+            "[::1]:4000",
+            "192.168.1.20:4000",
+        ] {
+            let response = warp::test::request()
+                .header("host", host)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", host);
+        }
+        for host in [
+            "evil.example:4000",
+            "localhost.evil.example",
+            "192.168.1.21",
+        ] {
+            let response = warp::test::request()
+                .header("host", host)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", host);
+        }
+        let response = warp::test::request().reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_cross_origin_websockets() {
+        let (_tx, rx) = watch::channel(FileState::default());
+        let routes = ws_route(rx, Clients::new("doc")).recover(handle_rejection);
+
+        let cross_site = warp::test::ws()
+            .path("/ws")
+            .header("host", "localhost:4000")
+            .header("origin", "http://localhost:3000")
+            .handshake(routes.clone())
+            .await;
+        assert!(cross_site.is_err());
+
+        let same_site = warp::test::ws()
+            .path("/ws")
+            .header("host", "localhost:4000")
+            .header("origin", "http://localhost:4000")
+            .handshake(routes)
+            .await;
+        assert!(same_site.is_ok());
+    }
+
+    // A route answering with a body streamed in chunks, as the API may
+    fn chunked_route() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+        warp::path!("api" / "info")
+            .and(warp::header::optional::<String>("authorization"))
+            .map(|authorization: Option<String>| {
+                let (mut sender, body) = hyper::Body::channel();
+                tokio::spawn(async move {
+                    for chunk in [r#"{"authorization":"#, &format!("{:?}", authorization), "}"] {
+                        sender.send_data(chunk.to_string().into()).await.unwrap();
+                    }
+                });
+                warp::reply::Response::new(body)
+            })
+    }
+
+    #[tokio::test]
+    async fn reads_chunked_api_responses() {
+        let (addr, server) = warp::serve(chunked_route()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let endpoint = Endpoint {
+            bind: addr.ip(),
+            port: addr.port(),
+            tls: false,
+        };
+
+        let (status, body) = api_request(endpoint, "GET", "/api/info", Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"authorization":Some("Bearer secret")}"#);
+        let (status, _) = api_request(endpoint, "GET", "/missing", None)
+            .await
+            .unwrap();
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn makes_api_requests_over_tls() {
+        let identity = tls::temporary_identity(&Config::default(), &[]).unwrap();
+        let (addr, server) = warp::serve(chunked_route())
+            .tls()
+            .cert(identity.cert)
+            .key(identity.key)
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let endpoint = Endpoint {
+            bind: addr.ip(),
+            port: addr.port(),
+            tls: true,
+        };
+
+        let (status, body) = api_request(endpoint, "GET", "/api/info", None)
+            .await
+            .unwrap();
+        assert_eq!((status, body.as_str()), (200, r#"{"authorization":None}"#));
+    }
+
+    #[tokio::test]
+    async fn reports_refused_api_connections() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let endpoint = Endpoint {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            tls: false,
+        };
+
+        let error = api_request(endpoint, "GET", "/api/health", None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::ConnectionRefused)
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_pages_to_reconnect() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        // The page reconnects by the third request
+        let route = warp::path!("api" / "focus").map(move || {
+            let clients = (counted.fetch_add(1, Ordering::SeqCst) >= 2) as u64;
+            warp::reply::json(&serde_json::json!({ "clients": clients }))
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let endpoint = Endpoint {
+            bind: addr.ip(),
+            port: addr.port(),
+            tls: false,
+        };
+
+        assert_eq!(wait_for_pages(endpoint, None, RECONNECT_WAIT).await, 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Without any page it gives up
+        requests.store(0, Ordering::SeqCst);
+        assert_eq!(wait_for_pages(endpoint, None, Duration::ZERO).await, 0);
+    }
 }
@@ -0,0 +1,219 @@
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tracing::info;
+
+use crate::config::Config;
+use crate::utils;
+
+// Subdirectory of the state directory holding the generated certificate
+const TLS_DIR: &str = "tls";
+
+// Certificate and private key (PEM) a server uses for HTTPS
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+// The configured certificate, or a self-signed one generated on first use and
+// kept in the state directory. A generated certificate names localhost and
+// the given addresses; the names are kept alongside it, and it is generated
+// again, for the old and new names, when an address is missing from them.
+pub fn server_identity(config: &Config, addresses: &[IpAddr]) -> Result<Identity> {
+    if let Some(identity) = configured_identity(config)? {
+        return Ok(identity);
+    }
+
+    let dir = config.state_dir.join(TLS_DIR);
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    let names_path = dir.join("names");
+    let mut names: Vec<String> = fs::read_to_string(&names_path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    let missing: Vec<String> = certificate_names(addresses)
+        .into_iter()
+        .filter(|name| !names.contains(name))
+        .collect();
+    if missing.is_empty() {
+        if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+            return Ok(Identity { cert, key });
+        }
+    }
+
+    names.extend(missing);
+    let identity = self_signed_identity(&names)?;
+    utils::file::create_private_subdir(&dir)?;
+    fs::write(&cert_path, &identity.cert)
+        .context(format!("Failed to write certificate to {:?}", cert_path))?;
+    utils::file::write_private_file(&key_path, &String::from_utf8_lossy(&identity.key))?;
+    fs::write(&names_path, names.join("\n") + "\n")
+        .context(format!("Failed to write {:?}", names_path))?;
+    info!(
+        "Generated a self-signed certificate for {} at {:?}",
+        names.join(", "),
+        cert_path
+    );
+
+    Ok(identity)
+}
+
+// The configured certificate, or a self-signed one that only lasts as long
+// as this process, for servers that keep nothing in the state directory
+pub fn temporary_identity(config: &Config, addresses: &[IpAddr]) -> Result<Identity> {
+    match configured_identity(config)? {
+        Some(identity) => Ok(identity),
+        None => {
+            info!("Using a temporary self-signed certificate");
+            self_signed_identity(&certificate_names(addresses))
+        }
+    }
+}
+
+// The certificate and key named by tls_cert and tls_key
+fn configured_identity(config: &Config) -> Result<Option<Identity>> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Ok(None),
+    };
+    let cert = fs::read(cert_path).context(format!("Failed to read {:?}", cert_path))?;
+    let key = fs::read(key_path).context(format!("Failed to read {:?}", key_path))?;
+    Ok(Some(Identity { cert, key }))
+}
+
+// The names a generated certificate needs: localhost and the given addresses
+fn certificate_names(addresses: &[IpAddr]) -> Vec<String> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    for address in addresses {
+        let name = address.to_string();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+// A self-signed certificate for the given names
+fn self_signed_identity(names: &[String]) -> Result<Identity> {
+    let cert = rcgen::generate_simple_self_signed(names.to_vec())
+        .context("Failed to generate a self-signed certificate")?;
+    Ok(Identity {
+        cert: cert.serialize_pem()?.into_bytes(),
+        key: cert.serialize_private_key_pem().into_bytes(),
+    })
+}
+
+// Open a TLS connection to a local server, for marv's own requests. These
+// carry the server's control token rather than relying on the certificate,
+// which is usually self-signed, so any certificate is accepted.
+pub async fn connect(stream: TcpStream, addr: SocketAddr) -> Result<TlsStream<TcpStream>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::from(addr.ip()), stream)
+        .await
+        .context(format!("TLS handshake with {} failed", addr))
+}
+
+// Accepts any server certificate, while still checking the handshake is
+// signed by it
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_the_certificate_again_for_new_addresses() {
+        let dir = utils::file::test_dir("tls-names");
+        let config = Config {
+            state_dir: dir.clone(),
+            tls_cert: None,
+            tls_key: None,
+            ..Config::default()
+        };
+        let lan: IpAddr = "192.168.1.2".parse().unwrap();
+        let names = || fs::read_to_string(dir.join("tls/names")).unwrap();
+
+        let local = server_identity(&config, &[]).unwrap();
+        assert_eq!(names(), "localhost\n127.0.0.1\n::1\n");
+        // Reused while it names every address
+        let again = server_identity(&config, &["127.0.0.1".parse().unwrap()]).unwrap();
+        assert_eq!(again.cert, local.cert);
+
+        let shared = server_identity(&config, &[lan]).unwrap();
+        assert_ne!(shared.cert, local.cert);
+        assert_eq!(names(), "localhost\n127.0.0.1\n::1\n192.168.1.2\n");
+        // The old names are kept, so local servers can use it too
+        assert_eq!(server_identity(&config, &[]).unwrap().cert, shared.cert);
+
+        // A certificate from before names were kept is replaced
+        fs::remove_file(dir.join("tls/names")).unwrap();
+        assert_ne!(server_identity(&config, &[]).unwrap().cert, shared.cert);
+    }
+}
@@ -1,49 +1,280 @@
-use std::fs::{self, File};
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Component, Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
-use tracing::{debug, info};
+use encoding_rs::{Encoding, UTF_8};
+use tracing::{debug, info, warn};
 
-// Server information storage
-const SERVER_INFO_DIR: &str = ".marv";
+use crate::config::Config;
 
-// Get or create the directory for server information
-pub fn get_server_info_dir() -> Result<PathBuf> {
-    let home_dir = dirs::home_dir().ok_or_else(|| anyhow!("Could not determine home directory"))?;
+// User customisation files
+const CONFIG_DIR: &str = "marv";
+const DIRECTORY_STYLESHEET: &str = ".marv.css";
 
-    let server_dir = home_dir.join(SERVER_INFO_DIR);
+// Subdirectory of the state directory remembering each document's last port
+const PORTS_DIR: &str = "ports";
 
-    if !server_dir.exists() {
-        fs::create_dir_all(&server_dir).context("Failed to create server info directory")?;
-        info!("Created server info directory at {:?}", server_dir);
+// Directory in the home folder that earlier versions kept everything in
+const LEGACY_DIR: &str = ".marv";
+
+// File in the state directory recording that the legacy directory was migrated
+const MIGRATED_MARKER: &str = ".legacy-migrated";
+
+// Get the directory for server information. It is created when a server is
+// recorded, not here.
+pub fn get_server_info_dir(config: &Config) -> PathBuf {
+    migrate_legacy_dir(config);
+    config.server_dir().to_path_buf()
+}
+
+// Create a directory readable only by the user, and any missing parents with
+// the usual permissions. The directories marv keeps things in can be set to
+// any folder, so an existing one is left as it is.
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        warn_if_shared(path);
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create directory: {:?}", parent))?;
+    }
+
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(path)
+        .context(format!("Failed to create directory: {:?}", path))?;
+    info!("Created directory {:?}", path);
+    Ok(())
+}
+
+// Create one of marv's own subdirectories, such as tls/ or diagrams/, readable
+// only by the user. These belong to marv, so an existing one that others can
+// read, e.g. one created by an earlier version, is made private too.
+pub fn create_private_subdir(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        restrict_dir(path);
+        return Ok(());
+    }
+    create_private_dir(path)
+}
+
+// Mention an existing directory that other users can get into. Shared
+// directories such as /tmp (which have the sticky bit) are expected to be.
+#[cfg(target_family = "unix")]
+fn warn_if_shared(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 && mode & 0o1000 == 0 {
+            warn!("Directory {:?} can be accessed by other users", path);
+        }
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn warn_if_shared(_path: &Path) {}
+
+// Take away other users' access to an existing directory. Shared directories
+// such as /tmp (which have the sticky bit) are left alone, as is anything the
+// user doesn't own.
+#[cfg(target_family = "unix")]
+fn restrict_dir(path: &Path) {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    let mode = metadata.permissions().mode();
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let owned = metadata.uid() == unsafe { libc::geteuid() };
+    if !owned || mode & 0o077 == 0 || mode & 0o1000 != 0 {
+        return;
+    }
+    match fs::set_permissions(path, fs::Permissions::from_mode(0o700)) {
+        Ok(()) => info!("Made directory {:?} private", path),
+        Err(e) => warn!("Failed to make directory {:?} private: {}", path, e),
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn restrict_dir(_path: &Path) {}
+
+// Resolve . and .. in a path without looking at the filesystem, for paths
+// that can't be canonicalised because they don't exist yet
+pub fn normalise_path(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalised.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalised.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => (),
+                _ => normalised.push(component),
+            },
+            _ => normalised.push(component),
+        }
+    }
+    normalised
+}
+
+// Move what earlier versions kept in ~/.marv to where it now belongs
+fn migrate_legacy_dir(config: &Config) {
+    if let Some(home) = dirs::home_dir() {
+        migrate_dir(&home.join(LEGACY_DIR), config);
+    }
+}
+
+// Move marv's files out of a legacy directory: server records to the runtime
+// directory, logs and the certificate to the state directory. The diagram
+// cache is dropped, and the directory is removed if nothing else is left.
+// This only happens once: a marker in the state directory records that it
+// has, and anything left behind is the user's.
+fn migrate_dir(legacy_dir: &Path, config: &Config) {
+    let configured = [
+        config.state_dir.as_path(),
+        config.server_dir(),
+        config.cache_dir.as_path(),
+    ];
+    let marker = config.state_dir.join(MIGRATED_MARKER);
+    if !legacy_dir.is_dir() || configured.contains(&legacy_dir) || marker.exists() {
+        return;
+    }
+
+    info!("Moving marv's files out of {:?}", legacy_dir);
+    for entry in fs::read_dir(legacy_dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        let moved = if name_str == "cache" {
+            fs::remove_dir_all(&path).context(format!("Failed to remove {:?}", path))
+        } else if name_str == "tls" {
+            move_path(&path, &config.state_dir.join("tls"))
+        } else if name_str.contains(".log") {
+            move_path(&path, &config.state_dir.join("logs").join(&name))
+        } else if ["server", "filepath", "token", "access"]
+            .iter()
+            .any(|kind| path.extension().is_some_and(|ext| ext == *kind))
+        {
+            move_path(&path, &config.server_dir().join(&name))
+        } else {
+            continue;
+        };
+        if let Err(e) = moved {
+            warn!("Failed to move {:?}: {:#}", path, e);
+        }
+    }
+
+    match fs::remove_dir(legacy_dir) {
+        Ok(()) => info!("Removed {:?}", legacy_dir),
+        Err(e) => debug!("Keeping {:?}: {}", legacy_dir, e),
+    }
+
+    let marked = create_private_dir(&config.state_dir)
+        .and_then(|()| fs::write(&marker, "").context(format!("Failed to write {:?}", marker)));
+    if let Err(e) = marked {
+        warn!(
+            "Failed to record the migration of {:?}: {:#}",
+            legacy_dir, e
+        );
+    }
+}
+
+// Move a file or directory, copying it when it is on another filesystem
+// (e.g. to a tmpfs runtime directory). Nothing is overwritten: when the target
+// already exists, e.g. after an interrupted migration, the source is removed.
+fn move_path(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        debug!("{:?} already exists, removing {:?}", to, from);
+        let removed = if from.is_dir() {
+            fs::remove_dir_all(from)
+        } else {
+            fs::remove_file(from)
+        };
+        return removed.context(format!("Failed to remove {:?}", from));
+    }
+    if let Some(parent) = to.parent() {
+        create_private_dir(parent)?;
     }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    if from.is_dir() {
+        create_private_dir(to)?;
+        for entry in fs::read_dir(from)?.flatten() {
+            move_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        fs::remove_dir(from)?;
+    } else {
+        fs::copy(from, to).context(format!("Failed to copy {:?} to {:?}", from, to))?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
 
-    Ok(server_dir)
+// Identify a markdown file by a hash of its path
+pub fn get_file_hash(input_path: &Path) -> String {
+    format!(
+        "{:x}",
+        md5::compute(input_path.to_string_lossy().as_bytes())
+    )
 }
 
 // Get the server info file path for a specific markdown file
 pub fn get_server_info_path(input_path: &Path, server_dir: &Path) -> PathBuf {
     // Create a unique filename based on the input path
-    let file_hash = format!(
-        "{:x}",
-        md5::compute(input_path.to_string_lossy().as_bytes())
-    );
+    let file_hash = get_file_hash(input_path);
     let path = server_dir.join(format!("{}.server", file_hash));
     debug!("Server info path for {:?}: {:?}", input_path, path);
     path
 }
 
+// The port a document was last served on, if remembered
+pub fn read_last_port(config: &Config, input_path: &Path) -> Option<u16> {
+    let path = last_port_path(config, input_path);
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// Remember the port a document is served on, for the next time it is started
+pub fn write_last_port(config: &Config, input_path: &Path, port: u16) -> Result<()> {
+    let path = last_port_path(config, input_path);
+    if let Some(parent) = path.parent() {
+        create_private_dir(parent)?;
+    }
+    fs::write(&path, port.to_string()).context(format!("Failed to write {:?}", path))
+}
+
+fn last_port_path(config: &Config, input_path: &Path) -> PathBuf {
+    config
+        .state_dir
+        .join(PORTS_DIR)
+        .join(get_file_hash(input_path))
+}
+
 // Write server information to file
-pub fn write_server_info(server_info_path: &Path, port: u16, pid: u32) -> Result<()> {
-    let info = format!("{}:{}", port, pid);
+pub fn write_server_info(
+    server_info_path: &Path,
+    port: u16,
+    pid: u32,
+    bind: IpAddr,
+    tls: bool,
+) -> Result<()> {
+    let scheme = if tls { "https" } else { "http" };
+    let info = format!("{}:{}:{}:{}", port, pid, scheme, bind);
 
     // Ensure parent directory exists
     if let Some(parent) = server_info_path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)
-                .context(format!("Failed to create directory: {:?}", parent))?;
-        }
+        create_private_dir(parent)?;
     }
 
     fs::write(server_info_path, info).context(format!(
@@ -51,19 +282,74 @@ pub fn write_server_info(server_info_path: &Path, port: u16, pid: u32) -> Result
         server_info_path
     ))?;
     info!(
-        "Wrote server info to {:?}: port={}, pid={}",
-        server_info_path, port, pid
+        "Wrote server info to {:?}: port={}, pid={}, scheme={}, bind={}",
+        server_info_path, port, pid, scheme, bind
     );
     Ok(())
 }
 
+// Write a secret kept next to a server's record (e.g. "token"), readable only
+// by the user
+pub fn write_server_secret(server_info_path: &Path, kind: &str, secret: &str) -> Result<()> {
+    let token_path = server_info_path.with_extension(kind);
+    write_private_file(&token_path, secret)?;
+    debug!("Wrote server {} to {:?}", kind, token_path);
+    Ok(())
+}
+
+// Write a file readable only by the user
+pub fn write_private_file(path: &Path, content: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .context(format!("Failed to create {:?}", path))?;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+        .context(format!("Failed to write {:?}", path))?;
+    Ok(())
+}
+
+// Read a secret kept next to a server's record
+pub fn read_server_secret(server_info_path: &Path, kind: &str) -> Result<String> {
+    let token_path = server_info_path.with_extension(kind);
+    let token = fs::read_to_string(&token_path)
+        .context(format!("Failed to read token file {:?}", token_path))?;
+    Ok(token.trim().to_string())
+}
+
+// Remove a server's record and its file path reference. Files that are
+// already gone are ignored, as the server and its stopper may race.
+pub fn remove_server_info(server_info_path: &Path) -> Result<()> {
+    for path in [
+        server_info_path.to_path_buf(),
+        server_info_path.with_extension("filepath"),
+        server_info_path.with_extension("token"),
+        server_info_path.with_extension("access"),
+    ] {
+        match fs::remove_file(&path) {
+            Ok(()) => debug!("Removed {:?}", path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).context(format!("Failed to remove {:?}", path)),
+        }
+    }
+    Ok(())
+}
+
 // Read server information from file
 pub fn read_server_info(server_info_path: &Path) -> Result<(u16, u32)> {
     let content =
         fs::read_to_string(server_info_path).context("Failed to read server information")?;
 
-    let parts: Vec<&str> = content.trim().split(':').collect();
-    if parts.len() != 2 {
+    // port:pid, followed by the scheme and bind address in records from
+    // newer servers
+    let parts: Vec<&str> = content.trim().splitn(4, ':').collect();
+    if parts.len() < 2 {
         return Err(anyhow!("Invalid server info format"));
     }
 
@@ -81,16 +367,26 @@ pub fn read_server_info(server_info_path: &Path) -> Result<(u16, u32)> {
     Ok((port, pid))
 }
 
+// Read the address a server is bound to and whether it serves HTTPS,
+// assuming plain HTTP on loopback for old records
+pub fn read_server_endpoint(server_info_path: &Path) -> (IpAddr, bool) {
+    let content = fs::read_to_string(server_info_path).unwrap_or_default();
+    let parts: Vec<&str> = content.trim().splitn(4, ':').collect();
+    let tls = parts.get(2) == Some(&"https");
+    let bind = parts
+        .get(3)
+        .and_then(|bind| bind.parse().ok())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    (bind, tls)
+}
+
 // Save file path reference in the server info file
 pub fn save_file_path_in_server_info(server_info_path: &Path, file_path: &Path) -> Result<()> {
     let file_path_str = file_path.to_string_lossy().to_string();
 
     // Ensure parent directory exists
     if let Some(parent) = server_info_path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent)
-                .context(format!("Failed to create directory: {:?}", parent))?;
-        }
+        create_private_dir(parent)?;
     }
 
     let filepath_info_path = server_info_path.with_extension("filepath");
@@ -105,11 +401,352 @@ pub fn save_file_path_in_server_info(server_info_path: &Path, file_path: &Path)
     );
     Ok(())
 }
-// Read file content
-pub fn read_file(path: &Path) -> Result<String> {
-    let mut file = File::open(path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-    debug!("Read {} bytes from {:?}", content.len(), path);
-    Ok(content)
+
+// Read the file path saved next to a server's record
+pub fn read_file_path_in_server_info(server_info_path: &Path) -> Result<PathBuf> {
+    let filepath_info_path = server_info_path.with_extension("filepath");
+    let file_path = fs::read_to_string(&filepath_info_path).context(format!(
+        "Failed to read file path information from {:?}",
+        filepath_info_path
+    ))?;
+    Ok(PathBuf::from(file_path.trim()))
+}
+
+// Get the user configuration directory (~/.config/marv unless XDG_CONFIG_HOME is set)
+pub fn get_config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()?.join(".config"),
+    };
+    Some(base.join(CONFIG_DIR))
+}
+
+// Get the directory for files kept across restarts (~/.local/state/marv
+// unless XDG_STATE_HOME is set)
+pub fn get_state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+// Get the directory for caches (~/.cache/marv unless XDG_CACHE_HOME is set)
+pub fn get_cache_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+// Get the directory for running servers, if the session has one
+// (XDG_RUNTIME_DIR)
+pub fn get_runtime_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join(CONFIG_DIR)),
+        _ => None,
+    }
+}
+
+// marv's subdirectory of an XDG base directory, or of its default under the
+// home folder
+fn xdg_dir(variable: &str, default: &str) -> PathBuf {
+    let base = match std::env::var_os(variable) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()
+            .map(|home| home.join(default))
+            .unwrap_or_else(|| PathBuf::from(default)),
+    };
+    base.join(CONFIG_DIR)
+}
+
+// Get the files that customise a document's page: the global stylesheet, the
+// stylesheet next to the document and the page template. They may not exist.
+pub fn get_user_customisation_paths(config: &Config, input_path: &Path) -> Vec<PathBuf> {
+    let mut paths = user_stylesheet_paths(config, input_path);
+    paths.extend(config.template.clone());
+    paths
+}
+
+// Candidate stylesheets: the global one first, then the one next to the document
+fn user_stylesheet_paths(config: &Config, input_path: &Path) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(stylesheet) = &config.stylesheet {
+        candidates.push(stylesheet.clone());
+    }
+    if let Some(parent) = input_path.parent() {
+        candidates.push(parent.join(DIRECTORY_STYLESHEET));
+    }
+    candidates
+}
+
+// Read user stylesheets: the global one first, then the one next to the document
+pub fn read_user_stylesheets(config: &Config, input_path: &Path) -> Vec<String> {
+    user_stylesheet_paths(config, input_path)
+        .iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| match read_file(path, config.fallback_encoding()) {
+            Ok(content) => Some(content),
+            Err(e) => {
+                warn!("Failed to read stylesheet {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Read the user page template, if one exists
+pub fn read_user_template(config: &Config) -> Option<String> {
+    let path = config.template.as_ref()?;
+    if !path.is_file() {
+        return None;
+    }
+
+    match read_file(path, config.fallback_encoding()) {
+        Ok(content) => Some(content),
+        Err(e) => {
+            warn!("Failed to read page template {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+// A new, empty directory for a test's files
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("marv-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Read a text file's content, decoded like a document
+pub fn read_file(path: &Path, fallback: &'static Encoding) -> Result<String> {
+    Ok(decode_file(path, fallback)?.content)
+}
+
+// Text decoded from a file that may not be UTF-8
+#[derive(Debug, Clone)]
+pub struct DecodedFile {
+    // Content with line endings normalised to \n
+    pub content: String,
+    // Encoding the content was decoded from
+    pub encoding: &'static Encoding,
+    // First line that is not valid UTF-8, when the fallback encoding was used
+    pub invalid_utf8_line: Option<usize>,
+    // Whether some bytes could not be decoded and were replaced
+    pub lossy: bool,
+}
+
+// Read a text file, detecting its encoding from a byte order mark, then
+// trying UTF-8, then decoding with the fallback encoding. Decoding never
+// fails; undecodable bytes are replaced and reported through `lossy`.
+pub fn decode_file(path: &Path, fallback: &'static Encoding) -> Result<DecodedFile> {
+    let bytes = fs::read(path)?;
+
+    let mut invalid_utf8_line = None;
+    let (encoding, body) = match Encoding::for_bom(&bytes) {
+        Some((encoding, bom_length)) => (encoding, &bytes[bom_length..]),
+        None => match std::str::from_utf8(&bytes) {
+            Ok(_) => (UTF_8, &bytes[..]),
+            Err(e) => {
+                let valid = &bytes[..e.valid_up_to()];
+                invalid_utf8_line = Some(valid.iter().filter(|b| **b == b'\n').count() + 1);
+                (fallback, &bytes[..])
+            }
+        },
+    };
+
+    let (content, lossy) = encoding.decode_without_bom_handling(body);
+    let content = if content.contains('\r') {
+        content.replace("\r\n", "\n").replace('\r', "\n")
+    } else {
+        content.into_owned()
+    };
+    debug!(
+        "Read {} bytes from {:?} as {}",
+        bytes.len(),
+        path,
+        encoding.name()
+    );
+
+    Ok(DecodedFile {
+        content,
+        encoding,
+        invalid_utf8_line,
+        lossy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{UTF_16BE, UTF_16LE, WINDOWS_1252};
+
+    fn decode(name: &str, bytes: &[u8]) -> DecodedFile {
+        let path = test_dir("decode").join(name);
+        fs::write(&path, bytes).unwrap();
+        decode_file(&path, WINDOWS_1252).unwrap()
+    }
+
+    #[test]
+    fn decodes_byte_order_marks() {
+        let utf8 = decode("utf8.md", b"\xEF\xBB\xBF# Caf\xC3\xA9\n");
+        assert_eq!((utf8.content.as_str(), utf8.encoding), ("# Café\n", UTF_8));
+
+        let utf16le = decode("utf16le.md", b"\xFF\xFE#\x00 \x00\xE9\x00\n\x00");
+        assert_eq!(
+            (utf16le.content.as_str(), utf16le.encoding),
+            ("# é\n", UTF_16LE)
+        );
+
+        let utf16be = decode("utf16be.md", b"\xFE\xFF\x00#\x00 \x00\xE9\x00\n");
+        assert_eq!(
+            (utf16be.content.as_str(), utf16be.encoding),
+            ("# é\n", UTF_16BE)
+        );
+    }
+
+    #[test]
+    fn falls_back_for_invalid_utf8() {
+        let decoded = decode("latin.md", b"# Title\n\nCaf\xE9 \x80\n");
+        assert_eq!(decoded.content, "# Title\n\nCafé €\n");
+        assert_eq!(decoded.encoding, WINDOWS_1252);
+        assert_eq!(decoded.invalid_utf8_line, Some(3));
+        assert!(!decoded.lossy);
+
+        let utf8 = decode("utf8.md", "Café\n".as_bytes());
+        assert_eq!((utf8.encoding, utf8.invalid_utf8_line), (UTF_8, None));
+    }
+
+    #[test]
+    fn normalises_line_endings() {
+        let decoded = decode("endings.md", b"crlf\r\nlf\ncr\rend\r\n");
+        assert_eq!(decoded.content, "crlf\nlf\ncr\nend\n");
+    }
+
+    #[test]
+    fn reads_stylesheets_like_documents() {
+        let dir = test_dir("stylesheet");
+        fs::write(
+            dir.join(".marv.css"),
+            b"\xEF\xBB\xBFp::before { content: \"\xA7\" }\r\n",
+        )
+        .unwrap();
+        let config = Config {
+            stylesheet: None,
+            template: None,
+            ..Config::default()
+        };
+
+        let stylesheets = read_user_stylesheets(&config, &dir.join("doc.md"));
+        assert_eq!(stylesheets, ["p::before { content: \"\u{FFFD}\" }\n"]);
+        fs::write(
+            dir.join(".marv.css"),
+            b"p::before { content: \"\xA7\" }\r\n",
+        )
+        .unwrap();
+        let stylesheets = read_user_stylesheets(&config, &dir.join("doc.md"));
+        assert_eq!(stylesheets, ["p::before { content: \"§\" }\n"]);
+    }
+
+    #[test]
+    fn moves_legacy_files_to_their_directories() {
+        let dir = test_dir("migrate");
+        let legacy = dir.join("legacy");
+        fs::create_dir_all(legacy.join("cache")).unwrap();
+        fs::create_dir_all(legacy.join("tls")).unwrap();
+        for (name, content) in [
+            ("cache/abc.svg", "svg"),
+            ("tls/cert.pem", "old cert"),
+            ("marv.log", "log"),
+            ("abc.server", "4000:1"),
+            ("abc.token", "token"),
+            ("notes.txt", "mine"),
+        ] {
+            fs::write(legacy.join(name), content).unwrap();
+        }
+        let config = Config {
+            state_dir: dir.join("state"),
+            runtime_dir: Some(dir.join("run")),
+            cache_dir: dir.join("cache"),
+            ..Config::default()
+        };
+        // Already moved by an earlier, interrupted run
+        fs::create_dir_all(dir.join("state/tls")).unwrap();
+        fs::write(dir.join("state/tls/cert.pem"), "new cert").unwrap();
+
+        migrate_dir(&legacy, &config);
+        let read = |path: &str| fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("state/logs/marv.log"), "log");
+        assert_eq!(read("run/abc.server"), "4000:1");
+        assert_eq!(read("run/abc.token"), "token");
+        assert_eq!(read("state/tls/cert.pem"), "new cert");
+        // Only what marv doesn't know is left, so the directory stays
+        let left: Vec<_> = fs::read_dir(&legacy)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["notes.txt"]);
+
+        // Later runs leave it alone
+        fs::write(legacy.join("def.server"), "4001:2").unwrap();
+        migrate_dir(&legacy, &config);
+        assert!(legacy.join("def.server").exists());
+        assert!(!dir.join("run/def.server").exists());
+    }
+
+    #[test]
+    fn removes_the_legacy_directory_once_it_is_empty() {
+        let dir = test_dir("migrate-all");
+        let legacy = dir.join("legacy");
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("marv.log"), "log").unwrap();
+        let config = Config {
+            state_dir: dir.join("state"),
+            runtime_dir: Some(dir.join("run")),
+            cache_dir: dir.join("cache"),
+            ..Config::default()
+        };
+
+        migrate_dir(&legacy, &config);
+        assert!(!legacy.exists());
+        assert!(dir.join("state").join(MIGRATED_MARKER).exists());
+    }
+
+    #[test]
+    fn normalises_paths_that_do_not_exist() {
+        let normalise = |path: &str| normalise_path(Path::new(path));
+        assert_eq!(
+            normalise("/docs/notes/../img/./x.png"),
+            Path::new("/docs/img/x.png")
+        );
+        assert_eq!(normalise("/../x.png"), Path::new("/x.png"));
+        assert_eq!(normalise("../a/../../b"), Path::new("../../b"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn leaves_existing_directories_alone() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("private");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        let existing = dir.join("existing");
+        fs::create_dir(&existing).unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o755)).unwrap();
+        create_private_dir(&existing).unwrap();
+        assert_eq!(mode(&existing), 0o755);
+
+        create_private_dir(&dir.join("new")).unwrap();
+        assert_eq!(mode(&dir.join("new")), 0o700);
+
+        // marv's own subdirectories are made private
+        let subdir = existing.join("tls");
+        fs::create_dir(&subdir).unwrap();
+        fs::set_permissions(&subdir, fs::Permissions::from_mode(0o755)).unwrap();
+        create_private_subdir(&subdir).unwrap();
+        assert_eq!(mode(&subdir), 0o700);
+        assert_eq!(mode(&existing), 0o755);
+
+        // Shared directories are left alone
+        let shared = dir.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        create_private_subdir(&shared).unwrap();
+        assert_eq!(mode(&shared), 0o1777);
+    }
 }
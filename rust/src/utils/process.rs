@@ -1,7 +1,7 @@
 use std::process::Command;
 
-use anyhow::{Context, Result};
-use tracing::{debug, info};
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info, warn};
 
 // Check if a process is running
 pub fn is_process_running(pid: u32) -> bool {
@@ -91,10 +91,18 @@ pub fn kill_process(pid: u32) -> Result<()> {
     Ok(())
 }
 
-// Open the default browser
-pub fn open_browser(url: &str) {
+// Open a URL in the browser
+pub fn open_browser(url: &str, browser: Option<&str>) {
     info!("Opening browser to {}", url);
 
+    let browser_env = std::env::var("BROWSER").ok();
+    for command in browser_commands(browser, browser_env.as_deref()) {
+        match run_browser_command(&command, url) {
+            Ok(()) => return,
+            Err(e) => warn!("Failed to run browser {:?}: {}", command, e),
+        }
+    }
+
     #[cfg(target_os = "windows")]
     {
         Command::new("cmd").args(["/C", "start", url]).spawn().ok();
@@ -110,3 +118,66 @@ pub fn open_browser(url: &str) {
         Command::new("xdg-open").arg(url).spawn().ok();
     }
 }
+
+// The browser commands to try in turn: the configured one, or else those
+// listed in $BROWSER, which separates alternatives with colons on unix. A
+// configured command is used as written, as it may contain colons of its own.
+fn browser_commands(browser: Option<&str>, browser_env: Option<&str>) -> Vec<String> {
+    let commands: Vec<&str> = match (browser, browser_env) {
+        (Some(browser), _) => vec![browser],
+        #[cfg(target_family = "unix")]
+        (None, Some(browser_env)) => browser_env.split(':').collect(),
+        #[cfg(not(target_family = "unix"))]
+        (None, Some(browser_env)) => vec![browser_env],
+        (None, None) => Vec::new(),
+    };
+    commands
+        .into_iter()
+        .filter(|command| !command.trim().is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Run a browser command such as "firefox --new-window". The URL replaces a %s
+// argument, or is appended.
+fn run_browser_command(command: &str, url: &str) -> Result<()> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| anyhow!("Empty browser command"))?;
+    let mut args: Vec<String> = words.map(|word| word.replace("%s", url)).collect();
+    if !command.contains("%s") {
+        args.push(url.to_string());
+    }
+
+    Command::new(program)
+        .args(&args)
+        .spawn()
+        .context(format!("Failed to start {:?}", program))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_a_configured_browser_as_written() {
+        let commands = browser_commands(
+            Some("chromium --proxy-server=http://proxy:8080"),
+            Some("firefox:lynx"),
+        );
+        assert_eq!(commands, ["chromium --proxy-server=http://proxy:8080"]);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn tries_each_browser_listed_in_the_environment() {
+        assert_eq!(
+            browser_commands(None, Some("missing-browser:firefox --new-window::")),
+            ["missing-browser", "firefox --new-window"]
+        );
+        assert!(browser_commands(None, Some("")).is_empty());
+        assert!(browser_commands(None, None).is_empty());
+    }
+}
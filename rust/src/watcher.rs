@@ -1,57 +1,793 @@
+use std::collections::{BTreeSet, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use futures::channel::mpsc::channel;
-use futures::{SinkExt, StreamExt};
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use futures::channel::mpsc::{channel, Sender};
+use futures::{SinkExt, Stream, StreamExt};
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
 use tokio::sync::watch;
 use tracing::{debug, info, warn};
 
+use crate::config::{Config, WatcherMode};
+use crate::renderer;
 use crate::utils::file;
 
-// Watch file for changes
-pub async fn watch_file(path: PathBuf, watch_tx: Arc<Mutex<watch::Sender<String>>>) -> Result<()> {
+// Latest known state of the watched file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileState {
+    // Last content read from the file, kept while it is missing
+    pub content: String,
+    // Last rendered page, kept while the file is missing
+    pub html: String,
+    // Local files the rendered page depends on
+    pub dependencies: BTreeSet<PathBuf>,
+    // Whether the file is currently missing
+    pub deleted: bool,
+    // Incremented on every published change, so dependency-only changes
+    // are distinguishable even when the HTML is identical
+    pub revision: u64,
+    // Why the latest change could not be shown, while the last good render
+    // stays on screen
+    pub error: Option<RenderError>,
+    // A problem with the rendered content, such as lossy decoding
+    pub warning: Option<RenderError>,
+    // Nonce of the rendered page's own scripts, for its Content-Security-Policy
+    pub nonce: String,
+}
+
+// A problem reading or rendering the file, reported to the browser
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RenderError {
+    pub message: String,
+    // 1-based line in the file the error refers to, when known
+    pub line: Option<usize>,
+}
+
+// What a file event touched
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    None,
+    Document,
+    Dependency,
+    // The watcher itself reported an error
+    Failed,
+}
+
+// Filesystem types that don't deliver native change notifications reliably
+#[cfg(target_os = "linux")]
+const POLLED_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "afs",
+    "9p",
+    "drvfs",
+    "vboxsf",
+    "fakeowner",
+    "fuse.sshfs",
+    "fuse.grpcfuse",
+];
+
+// Read and render a file
+pub fn render_file(path: &Path, config: &Config) -> Result<FileState> {
+    let decoded = file::decode_file(path, config.fallback_encoding())?;
+    Ok(render_content(path, decoded, config))
+}
+
+// Render content read from a file
+fn render_content(path: &Path, decoded: file::DecodedFile, config: &Config) -> FileState {
+    let warning = decode_warning(&decoded);
+    if let Some(warning) = &warning {
+        warn!("{:?}: {}", path, warning.message);
+    }
+    let content = decoded.content;
+
+    let render_started = Instant::now();
+    let options = renderer::RenderOptions::load(config, path);
+    let filename = path.to_string_lossy();
+    let page = if renderer::is_mermaid_file(path) {
+        renderer::mermaid_to_html(&content, &filename, &options)
+    } else {
+        renderer::markdown_to_html(&content, &filename, &options)
+    };
+    debug!("Rendered {:?} in {:?}", path, render_started.elapsed());
+
+    // Customisation files are read outside the renderer but affect the page too
+    let mut dependencies = page.dependencies;
+    dependencies.extend(file::get_user_customisation_paths(config, path));
+
+    // Match the canonical paths reported by the file watcher where possible,
+    // and at least resolve .. in files that don't exist yet
+    let dependencies = dependencies
+        .into_iter()
+        .map(|dependency| {
+            std::fs::canonicalize(&dependency).unwrap_or_else(|_| file::normalise_path(&dependency))
+        })
+        .filter(|dependency| dependency != path)
+        .collect();
+
+    FileState {
+        content,
+        html: page.html,
+        dependencies,
+        deleted: false,
+        revision: 0,
+        error: None,
+        warning,
+        nonce: options.script_nonce,
+    }
+}
+
+// Watch file and the files it depends on for changes
+pub async fn watch_file(
+    path: PathBuf,
+    watch_tx: Arc<Mutex<watch::Sender<FileState>>>,
+    config: &Config,
+) -> Result<()> {
     info!("Starting file watcher for {:?}", path);
 
-    let (mut tx, mut rx) = channel(100);
-    let mut watcher = RecommendedWatcher::new(
-        move |res| {
-            futures::executor::block_on(async {
-                tx.send(res).await.unwrap();
-            })
-        },
-        Config::default(),
-    )?;
-
-    watcher.watch(
-        path.parent().unwrap_or(Path::new(".")),
-        RecursiveMode::NonRecursive,
-    )?;
-    debug!(
-        "Watching directory: {:?}",
-        path.parent().unwrap_or(Path::new("."))
+    let (tx, mut rx) = channel(100);
+    let document_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let can_fall_back = config.watcher == WatcherMode::Auto;
+
+    let mut polling = match config.watcher {
+        WatcherMode::Native => false,
+        WatcherMode::Poll => true,
+        WatcherMode::Auto => is_polled_filesystem(&document_dir),
+    };
+    let mut watcher = match open_watcher(polling, &tx, poll_interval, &document_dir) {
+        Ok(watcher) => watcher,
+        Err(e) if !polling && can_fall_back => {
+            warn!(
+                "Native file watcher failed ({}), falling back to polling",
+                e
+            );
+            polling = true;
+            open_watcher(polling, &tx, poll_interval, &document_dir)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut watched_dirs = HashSet::from([document_dir.clone()]);
+    let mut dependencies = watch_tx.lock().unwrap().borrow().dependencies.clone();
+    update_watches(
+        watcher.as_mut(),
+        &document_dir,
+        &mut watched_dirs,
+        &dependencies,
     );
 
+    let debounce = Duration::from_millis(config.debounce_ms);
+    let mut last_hash = file::decode_file(&path, config.fallback_encoding())
+        .ok()
+        .map(|decoded| md5::compute(decoded.content.as_bytes()));
+
     while let Some(res) = rx.next().await {
-        match res {
-            Ok(event) => {
-                if let Event {
-                    kind: notify::EventKind::Modify(_),
-                    ..
-                } = event
-                {
-                    debug!("File modification detected: {:?}", path);
-                    if let Ok(content) = file::read_file(&path) {
-                        let tx = watch_tx.lock().unwrap();
-                        let _ = tx.send(content);
-                        debug!("Updated content sent to server");
-                    }
-                }
+        let change = classify(res, &path, &dependencies);
+        if change == Change::None {
+            continue;
+        }
+
+        // Events may have been lost, so switch to polling and re-read everything
+        if change == Change::Failed {
+            if polling || !can_fall_back {
+                continue;
+            }
+            warn!("Native file watcher failed, falling back to polling");
+            polling = true;
+            watcher = open_watcher(polling, &tx, poll_interval, &document_dir)?;
+            watched_dirs = HashSet::from([document_dir.clone()]);
+            update_watches(
+                watcher.as_mut(),
+                &document_dir,
+                &mut watched_dirs,
+                &dependencies,
+            );
+        }
+
+        let first_event = Instant::now();
+        let (coalesced, dependency_changed) =
+            settle(&mut rx, debounce, change, &path, &dependencies).await;
+
+        // Rendering may take a while with diagram tools, so it runs on a
+        // blocking thread
+        let read_started = Instant::now();
+        let (task_path, task_config, task_tx) = (path.clone(), config.clone(), watch_tx.clone());
+        let (new_dependencies, hash) = tokio::task::spawn_blocking(move || {
+            let mut hash = last_hash;
+            let dependencies = sync_file_state(
+                &task_path,
+                &task_config,
+                &task_tx,
+                &mut hash,
+                dependency_changed,
+            );
+            (dependencies, hash)
+        })
+        .await?;
+        last_hash = hash;
+        debug!(
+            "Processed {} event(s) for {:?}: waited {:?}, read and rendered in {:?}",
+            coalesced,
+            path,
+            read_started - first_event,
+            read_started.elapsed()
+        );
+
+        if let Some(new_dependencies) = new_dependencies {
+            if new_dependencies != dependencies {
+                update_watches(
+                    watcher.as_mut(),
+                    &document_dir,
+                    &mut watched_dirs,
+                    &new_dependencies,
+                );
+                dependencies = new_dependencies;
             }
-            Err(e) => warn!("Watch error: {:?}", e),
         }
     }
 
     Ok(())
 }
+
+// Editors often produce several events for one save; wait for a quiet period
+// after the first change before re-reading so the burst results in a single
+// update. Returns how many changes were coalesced and whether any of them
+// could have changed a dependency.
+async fn settle(
+    rx: &mut (impl Stream<Item = notify::Result<Event>> + Unpin),
+    debounce: Duration,
+    change: Change,
+    path: &Path,
+    dependencies: &BTreeSet<PathBuf>,
+) -> (usize, bool) {
+    let mut coalesced = 1;
+    let mut dependency_changed = matches!(change, Change::Dependency | Change::Failed);
+    if debounce.is_zero() {
+        return (coalesced, dependency_changed);
+    }
+
+    let mut deadline = tokio::time::Instant::now() + debounce;
+    while let Ok(Some(res)) = tokio::time::timeout_at(deadline, rx.next()).await {
+        let change = classify(res, path, dependencies);
+        if matches!(change, Change::Document | Change::Dependency) {
+            coalesced += 1;
+            dependency_changed |= change == Change::Dependency;
+            deadline = tokio::time::Instant::now() + debounce;
+        }
+    }
+    (coalesced, dependency_changed)
+}
+
+// Work out which watched file, if any, a watcher result refers to
+fn classify(res: notify::Result<Event>, path: &Path, dependencies: &BTreeSet<PathBuf>) -> Change {
+    match res {
+        Ok(event) => {
+            let change = classify_event(&event, path, dependencies);
+            if change != Change::None {
+                debug!("File change detected ({:?}): {:?}", event.kind, event.paths);
+            }
+            change
+        }
+        Err(e) => {
+            warn!("Watch error: {:?}", e);
+            Change::Failed
+        }
+    }
+}
+
+// Creates, modifications, renames and removals are all handled the same way:
+// the file's current state on disk is what matters, not the kind of event
+// that reported it.
+fn classify_event(event: &Event, path: &Path, dependencies: &BTreeSet<PathBuf>) -> Change {
+    let kind_matches = matches!(
+        event.kind,
+        EventKind::Any | EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    );
+    if !kind_matches {
+        return Change::None;
+    }
+
+    if event.paths.iter().any(|p| p == path) {
+        Change::Document
+    } else if event.paths.iter().any(|p| dependencies.contains(p)) {
+        Change::Dependency
+    } else {
+        Change::None
+    }
+}
+
+// Create a native or polling watcher and watch the document's directory. The
+// directory is watched rather than the file, so that editors which save by
+// renaming a temporary file over the original don't break the watch.
+fn open_watcher(
+    polling: bool,
+    tx: &Sender<notify::Result<Event>>,
+    poll_interval: Duration,
+    document_dir: &Path,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    let mut tx = tx.clone();
+    let handler = move |res| {
+        futures::executor::block_on(async {
+            tx.send(res).await.unwrap();
+        })
+    };
+
+    let mut watcher: Box<dyn Watcher + Send> = if polling {
+        info!("Polling for file changes every {:?}", poll_interval);
+        let config = notify::Config::default().with_poll_interval(poll_interval);
+        Box::new(PollWatcher::new(handler, config)?)
+    } else {
+        Box::new(RecommendedWatcher::new(handler, notify::Config::default())?)
+    };
+
+    watcher.watch(document_dir, RecursiveMode::NonRecursive)?;
+    debug!("Watching directory: {:?}", document_dir);
+    Ok(watcher)
+}
+
+// Whether a directory is on a network or container filesystem that needs polling
+#[cfg(target_os = "linux")]
+fn is_polled_filesystem(dir: &Path) -> bool {
+    let mounts = match std::fs::read_to_string("/proc/self/mounts") {
+        Ok(mounts) => mounts,
+        Err(_) => return false,
+    };
+
+    match mount_filesystem(&mounts, dir) {
+        Some(fs_type) if POLLED_FILESYSTEMS.contains(&fs_type) => {
+            info!("{:?} is on a {} filesystem, using polling", dir, fs_type);
+            true
+        }
+        _ => false,
+    }
+}
+
+// The filesystem of the longest mount point in a mount table containing the directory
+#[cfg(target_os = "linux")]
+fn mount_filesystem<'a>(mounts: &'a str, dir: &Path) -> Option<&'a str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?.replace("\\040", " ");
+            let fs_type = fields.next()?;
+            Some((PathBuf::from(mount_point), fs_type))
+        })
+        .filter(|(mount_point, _)| dir.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, fs_type)| fs_type)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_polled_filesystem(_dir: &Path) -> bool {
+    false
+}
+
+// Watch the directories containing the dependencies, dropping ones no longer needed
+fn update_watches(
+    watcher: &mut dyn Watcher,
+    document_dir: &Path,
+    watched_dirs: &mut HashSet<PathBuf>,
+    dependencies: &BTreeSet<PathBuf>,
+) {
+    let mut needed: HashSet<PathBuf> = dependencies
+        .iter()
+        .filter_map(|dependency| dependency.parent())
+        .filter(|dir| dir.is_dir())
+        .map(Path::to_path_buf)
+        .collect();
+    needed.insert(document_dir.to_path_buf());
+
+    for dir in needed.difference(watched_dirs) {
+        match watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => debug!("Watching dependency directory: {:?}", dir),
+            Err(e) => warn!("Failed to watch {:?}: {}", dir, e),
+        }
+    }
+    for dir in watched_dirs.difference(&needed) {
+        if let Err(e) = watcher.unwatch(dir) {
+            debug!("Failed to unwatch {:?}: {}", dir, e);
+        }
+    }
+
+    *watched_dirs = needed;
+}
+
+// Re-read and render the file and publish its state, noting when it has
+// disappeared. Nothing is published when the content hash is unchanged and no
+// dependency changed. Returns the new dependencies after a render. The state
+// is only locked to read and publish it, not while rendering.
+fn sync_file_state(
+    path: &Path,
+    config: &Config,
+    watch_tx: &Mutex<watch::Sender<FileState>>,
+    last_hash: &mut Option<md5::Digest>,
+    dependency_changed: bool,
+) -> Option<BTreeSet<PathBuf>> {
+    if !path.exists() {
+        watch_tx.lock().unwrap().send_if_modified(|state| {
+            if state.deleted {
+                return false;
+            }
+            info!("File deleted: {:?}", path);
+            state.deleted = true;
+            state.revision += 1;
+            true
+        });
+        return None;
+    }
+
+    let (recovered, failed, revision) = {
+        let tx = watch_tx.lock().unwrap();
+        let state = tx.borrow();
+        (state.deleted, state.error.is_some(), state.revision)
+    };
+
+    let decoded = match file::decode_file(path, config.fallback_encoding()) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            let error = RenderError {
+                message: format!("Failed to read file: {:#}", e),
+                line: None,
+            };
+            warn!("Failed to read {:?}: {}", path, error.message);
+            watch_tx.lock().unwrap().send_if_modified(|state| {
+                if state.error.as_ref() == Some(&error) && !state.deleted {
+                    return false;
+                }
+                state.deleted = false;
+                state.error = Some(error);
+                state.revision += 1;
+                true
+            });
+            return None;
+        }
+    };
+    let hash = md5::compute(decoded.content.as_bytes());
+    if recovered {
+        info!("File reappeared: {:?}", path);
+    } else if !failed && !dependency_changed && *last_hash == Some(hash) {
+        debug!("Content unchanged, skipping update");
+        return None;
+    }
+    *last_hash = Some(hash);
+
+    let mut state = render_content(path, decoded, config);
+    state.revision = revision + 1;
+    let dependencies = state.dependencies.clone();
+    let _ = watch_tx.lock().unwrap().send(state);
+    debug!("Updated content sent to server");
+    Some(dependencies)
+}
+
+// Describe content that was not read as clean UTF-8
+fn decode_warning(decoded: &file::DecodedFile) -> Option<RenderError> {
+    let mut problems = Vec::new();
+    if decoded.invalid_utf8_line.is_some() {
+        problems.push(format!(
+            "File is not valid UTF-8, decoded as {}",
+            decoded.encoding.name()
+        ));
+    }
+    if decoded.lossy {
+        problems.push("some characters could not be decoded and were replaced".to_string());
+    }
+    if problems.is_empty() {
+        return None;
+    }
+
+    Some(RenderError {
+        message: problems.join("; "),
+        line: decoded.invalid_utf8_line,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| {
+            event.add_path(path.to_path_buf())
+        })
+    }
+
+    fn config() -> Config {
+        Config {
+            stylesheet: None,
+            template: None,
+            ..Config::default()
+        }
+    }
+
+    // A watched document with its rendered state and a server listening for updates
+    fn watched(
+        name: &str,
+        content: &str,
+    ) -> (
+        PathBuf,
+        Mutex<watch::Sender<FileState>>,
+        watch::Receiver<FileState>,
+    ) {
+        let path = file::test_dir(name).join("doc.md");
+        std::fs::write(&path, content).unwrap();
+        let state = render_file(&path, &config()).unwrap();
+        let (tx, rx) = watch::channel(state);
+        (path, Mutex::new(tx), rx)
+    }
+
+    #[test]
+    fn ignores_events_for_other_files() {
+        let path = Path::new("/docs/doc.md");
+        let none = BTreeSet::new();
+        for kind in [
+            EventKind::Modify(ModifyKind::Any),
+            EventKind::Create(CreateKind::File),
+        ] {
+            let sibling = event(kind, &[Path::new("/docs/other.md")]);
+            assert_eq!(classify_event(&sibling, path, &none), Change::None);
+        }
+
+        // Reading the document changes nothing
+        let access = event(EventKind::Access(notify::event::AccessKind::Any), &[path]);
+        assert_eq!(classify_event(&access, path, &none), Change::None);
+    }
+
+    #[test]
+    fn notices_every_way_of_saving_the_document() {
+        let path = Path::new("/docs/doc.md");
+        let none = BTreeSet::new();
+        let saves = [
+            event(EventKind::Modify(ModifyKind::Any), &[path]),
+            // A temporary file renamed over the document
+            event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &[Path::new("/docs/.doc.md.swp"), path],
+            ),
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &[path]),
+            // Removed, then written again
+            event(EventKind::Remove(RemoveKind::File), &[path]),
+            event(EventKind::Create(CreateKind::File), &[path]),
+        ];
+        for save in saves {
+            assert_eq!(
+                classify_event(&save, path, &none),
+                Change::Document,
+                "{:?}",
+                save
+            );
+        }
+    }
+
+    #[test]
+    fn tracks_a_document_that_is_deleted_and_reappears() {
+        let (path, tx, rx) = watched("watch-deleted", "# One");
+        let mut hash = None;
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            sync_file_state(&path, &config(), &tx, &mut hash, false),
+            None
+        );
+        let deleted = rx.borrow().clone();
+        assert!(deleted.deleted);
+        assert_eq!(deleted.revision, 1);
+        // The last render stays on screen
+        assert!(deleted.html.contains("One</h1>"));
+
+        // Nothing new to say while it stays missing
+        sync_file_state(&path, &config(), &tx, &mut hash, false);
+        assert_eq!(rx.borrow().revision, 1);
+
+        std::fs::write(&path, "# Two").unwrap();
+        assert!(sync_file_state(&path, &config(), &tx, &mut hash, false).is_some());
+        let reappeared = rx.borrow().clone();
+        assert!(!reappeared.deleted);
+        assert_eq!(reappeared.revision, 2);
+        assert!(reappeared.html.contains("Two</h1>"));
+    }
+
+    #[test]
+    fn reports_read_failures_until_the_document_can_be_read() {
+        let (path, tx, rx) = watched("watch-unreadable", "# One");
+        let mut hash = Some(md5::compute("# One"));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        assert_eq!(
+            sync_file_state(&path, &config(), &tx, &mut hash, false),
+            None
+        );
+        let failed = rx.borrow().clone();
+        let error = failed.error.expect("the failure is reported");
+        assert!(
+            error.message.starts_with("Failed to read file"),
+            "{}",
+            error.message
+        );
+        // The last render stays on screen
+        assert!(failed.html.contains("One</h1>"));
+        assert_eq!(failed.revision, 1);
+
+        // Once readable it is shown again, even though its content is the same
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::write(&path, "# One").unwrap();
+        assert!(sync_file_state(&path, &config(), &tx, &mut hash, false).is_some());
+        let recovered = rx.borrow().clone();
+        assert_eq!(recovered.error, None);
+        assert_eq!(recovered.revision, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_a_burst_of_events_to_settle() {
+        let path = Path::new("/docs/doc.md");
+        let (mut tx, mut rx) = channel(10);
+        let modified = || Ok(event(EventKind::Modify(ModifyKind::Any), &[path]));
+        tokio::spawn(async move {
+            for delay in [80, 80] {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                tx.send(modified()).await.unwrap();
+            }
+            // Other files don't hold the update back
+            tokio::time::sleep(Duration::from_millis(80)).await;
+            let other = event(
+                EventKind::Modify(ModifyKind::Any),
+                &[Path::new("/docs/x.md")],
+            );
+            tx.send(Ok(other)).await.unwrap();
+            // The next save is a separate update
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tx.send(modified()).await.unwrap();
+        });
+
+        let none = BTreeSet::new();
+        let started = tokio::time::Instant::now();
+        let debounce = Duration::from_millis(100);
+        let settled = settle(&mut rx, debounce, Change::Document, path, &none).await;
+        assert_eq!(settled, (3, false));
+        assert_eq!(started.elapsed(), Duration::from_millis(260));
+
+        // Without a debounce every event is handled on its own
+        let settled = settle(&mut rx, Duration::ZERO, Change::Document, path, &none).await;
+        assert_eq!(settled, (1, false));
+    }
+
+    #[test]
+    fn skips_updates_when_the_content_is_unchanged() {
+        let (path, tx, rx) = watched("watch-unchanged", "# One");
+        let mut hash = Some(md5::compute("# One"));
+
+        // Touched but not changed
+        std::fs::write(&path, "# One").unwrap();
+        assert_eq!(
+            sync_file_state(&path, &config(), &tx, &mut hash, false),
+            None
+        );
+        assert_eq!(rx.borrow().revision, 0);
+
+        std::fs::write(&path, "# Two").unwrap();
+        assert!(sync_file_state(&path, &config(), &tx, &mut hash, false).is_some());
+        assert_eq!(rx.borrow().revision, 1);
+        assert_eq!(hash, Some(md5::compute("# Two")));
+    }
+
+    // Records what is being watched instead of watching it
+    #[derive(Default)]
+    struct Recorder(BTreeSet<PathBuf>);
+
+    impl Watcher for Recorder {
+        fn new<F: notify::EventHandler>(_: F, _: notify::Config) -> notify::Result<Self> {
+            Ok(Recorder::default())
+        }
+
+        fn watch(&mut self, path: &Path, _: RecursiveMode) -> notify::Result<()> {
+            self.0.insert(path.to_path_buf());
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+            self.0.remove(path);
+            Ok(())
+        }
+
+        fn kind() -> notify::WatcherKind {
+            notify::WatcherKind::NullWatcher
+        }
+    }
+
+    #[test]
+    fn watches_the_directories_of_dependencies_while_they_are_needed() {
+        let dir = file::test_dir("watch-dependencies");
+        let (styles, images) = (dir.join("styles"), dir.join("images"));
+        std::fs::create_dir_all(&styles).unwrap();
+        std::fs::create_dir_all(&images).unwrap();
+
+        let mut watcher = Recorder::default();
+        let mut watched = HashSet::from([dir.clone()]);
+        watcher.watch(&dir, RecursiveMode::NonRecursive).unwrap();
+        let mut update = |dependencies: &[PathBuf]| {
+            let dependencies = dependencies.iter().cloned().collect();
+            update_watches(&mut watcher, &dir, &mut watched, &dependencies);
+            assert_eq!(watched, watcher.0.iter().cloned().collect());
+            watcher.0.clone()
+        };
+
+        // Grows with the dependencies, watching each directory once
+        let grown = update(&[
+            styles.join("a.css"),
+            styles.join("b.css"),
+            images.join("c.png"),
+            dir.join("missing/d.png"),
+        ]);
+        assert_eq!(
+            grown,
+            BTreeSet::from([dir.clone(), images.clone(), styles.clone()])
+        );
+
+        // And shrinks again, but the document's own directory stays
+        let shrunk = update(&[styles.join("a.css")]);
+        assert_eq!(shrunk, BTreeSet::from([dir.clone(), styles.clone()]));
+        assert_eq!(update(&[]), BTreeSet::from([dir.clone()]));
+    }
+
+    #[test]
+    fn re_renders_when_a_dependency_changes() {
+        let path = Path::new("/docs/doc.md");
+        let stylesheet = PathBuf::from("/docs/style.css");
+        let dependencies = BTreeSet::from([stylesheet.clone()]);
+        let modified = event(EventKind::Modify(ModifyKind::Any), &[&stylesheet]);
+        assert_eq!(
+            classify_event(&modified, path, &dependencies),
+            Change::Dependency
+        );
+        let unrelated = event(
+            EventKind::Modify(ModifyKind::Any),
+            &[Path::new("/docs/x.css")],
+        );
+        assert_eq!(
+            classify_event(&unrelated, path, &dependencies),
+            Change::None
+        );
+
+        // The document itself is unchanged but must be rendered again
+        let (path, tx, rx) = watched("watch-dependency-changed", "# One");
+        let mut hash = Some(md5::compute("# One"));
+        assert!(sync_file_state(&path, &config(), &tx, &mut hash, true).is_some());
+        assert_eq!(rx.borrow().revision, 1);
+
+        // Including one that doesn't exist yet, which is found by the path
+        // the watcher will report once it is created
+        let (path, _, rx) = watched("watch-dependency-missing", "![x](../img/x.png)");
+        let dir = path.parent().unwrap().canonicalize().unwrap();
+        let expected = dir.parent().unwrap().join("img").join("x.png");
+        assert!(rx.borrow().dependencies.contains(&expected));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn finds_the_filesystem_a_directory_is_on() {
+        let mounts = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+server:/export /home/me/shared nfs4 rw,relatime 0 0
+/dev/sdb1 /home/me/shared/local ext4 rw 0 0
+//nas/docs /mnt/my\\040docs cifs rw 0 0
+";
+        let filesystem = |dir: &str| mount_filesystem(mounts, Path::new(dir));
+        assert_eq!(filesystem("/home/me/notes"), Some("ext4"));
+        assert_eq!(filesystem("/home/me/shared/notes"), Some("nfs4"));
+        // The most specific mount point wins
+        assert_eq!(filesystem("/home/me/shared/local/notes"), Some("ext4"));
+        // Only whole path components match
+        assert_eq!(filesystem("/home/me/shared-not"), Some("ext4"));
+        assert_eq!(filesystem("/mnt/my docs/notes"), Some("cifs"));
+        assert_eq!(mount_filesystem("", Path::new("/home")), None);
+    }
+}